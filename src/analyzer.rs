@@ -0,0 +1,416 @@
+use std::io::Write;
+
+use crate::h264::{self, FrameRate, NalSyntax, NalUnitType, SeiMessage, SeiPayload, SliceHeader, SliceType, NAL};
+use crate::mux::DEFAULT_FRAME_RATE;
+
+// Field value of a line of the analyzer output
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    List(Vec<Value>),
+    Object(Vec<(&'static str, Value)>),
+}
+
+impl Value {
+    fn json(&self, out: &mut String) {
+        match self {
+            Value::Int(v) => out.push_str(&v.to_string()),
+            // JSON has no NaN or infinity, e.g. the bitrate of a group without duration
+            Value::Float(v) if !v.is_finite() => out.push_str("null"),
+            Value::Float(v) => out.push_str(&format!("{:.3}", v)),
+            Value::Bool(v) => out.push_str(if *v { "true" } else { "false" }),
+            Value::Str(v) => {
+                out.push('"');
+                for c in v.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Value::List(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 { out.push(','); }
+                    value.json(out);
+                }
+                out.push(']');
+            }
+            Value::Object(fields) => {
+                out.push('{');
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 { out.push(','); }
+                    out.push_str(&format!("\"{}\":", name));
+                    value.json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    fn text(&self, out: &mut String) {
+        match self {
+            Value::Str(v) => out.push_str(v),
+            Value::List(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 { out.push_str(", "); }
+                    value.text(out);
+                }
+                out.push(']');
+            }
+            Value::Object(fields) => {
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 { out.push(' '); }
+                    out.push_str(name);
+                    out.push('=');
+                    value.text(out);
+                }
+            }
+            value => value.json(out),
+        }
+    }
+}
+
+impl From<u32> for Value { fn from(v: u32) -> Value { Value::Int(v as i64) } }
+impl From<i32> for Value { fn from(v: i32) -> Value { Value::Int(v as i64) } }
+impl From<u8> for Value { fn from(v: u8) -> Value { Value::Int(v as i64) } }
+impl From<usize> for Value { fn from(v: usize) -> Value { Value::Int(v as i64) } }
+impl From<u64> for Value { fn from(v: u64) -> Value { Value::Int(v as i64) } }
+impl From<f64> for Value { fn from(v: f64) -> Value { Value::Float(v) } }
+impl From<bool> for Value { fn from(v: bool) -> Value { Value::Bool(v) } }
+impl From<&str> for Value { fn from(v: &str) -> Value { Value::Str(v.to_string()) } }
+impl From<String> for Value { fn from(v: String) -> Value { Value::Str(v) } }
+
+// Fields of one line of output, in the order they are printed
+#[derive(Default)]
+struct Record(Vec<(&'static str, Value)>);
+
+impl Record {
+    fn new(kind: &'static str) -> Record { Record(vec![("kind", kind.into())]) }
+    fn add(&mut self, name: &'static str, value: impl Into<Value>) { self.0.push((name, value.into())); }
+
+    // JSON mode prints one object per line, text mode `kind name=value ...`
+    fn print(self, out: &mut impl Write, json: bool) {
+        let line = self.line(json);
+        // a closed pipe, e.g. into head, ends the output without a panic
+        let _ = writeln!(out, "{}", line);
+    }
+
+    fn line(self, json: bool) -> String {
+        let mut out = String::new();
+        if json {
+            Value::Object(self.0).json(&mut out);
+        } else {
+            let mut fields = self.0.into_iter();
+            if let Some((_, kind)) = fields.next() { kind.text(&mut out); }
+            for (name, value) in fields {
+                out.push(' ');
+                out.push_str(name);
+                out.push('=');
+                value.text(&mut out);
+            }
+        }
+        out
+    }
+}
+
+fn slice_type_name(slice_type: Option<SliceType>) -> &'static str {
+    match slice_type {
+        Some(SliceType::P) => "P",
+        Some(SliceType::B) => "B",
+        Some(SliceType::I) => "I",
+        Some(SliceType::SP) => "SP",
+        Some(SliceType::SI) => "SI",
+        None => "?",
+    }
+}
+
+fn sps_fields(record: &mut Record, sps: &h264::Sps) {
+    record.add("seq_parameter_set_id", sps.seq_parameter_set_id);
+    record.add("profile_idc", sps.profile_idc);
+    record.add("constraint_flags", sps.constraint_flags);
+    record.add("level_idc", sps.level_idc);
+    record.add("chroma_format_idc", sps.chroma_format_idc);
+    record.add("bit_depth_luma", sps.bit_depth_luma_minus8 + 8);
+    record.add("bit_depth_chroma", sps.bit_depth_chroma_minus8 + 8);
+    record.add("log2_max_frame_num", sps.log2_max_frame_num_minus4 + 4);
+    record.add("pic_order_cnt_type", sps.pic_order_cnt_type);
+    if sps.pic_order_cnt_type == 0 { record.add("log2_max_pic_order_cnt_lsb", sps.log2_max_pic_order_cnt_lsb_minus4 + 4); }
+    record.add("max_num_ref_frames", sps.max_num_ref_frames);
+    record.add("frame_mbs_only_flag", sps.frame_mbs_only_flag);
+    record.add("mb_adaptive_frame_field_flag", sps.mb_adaptive_frame_field_flag);
+    record.add("width", sps.width());
+    record.add("height", sps.height());
+    if let Some(vui) = &sps.vui {
+        if vui.timing_info_present_flag {
+            record.add("num_units_in_tick", vui.num_units_in_tick);
+            record.add("time_scale", vui.time_scale);
+        }
+        if let Some(max_num_reorder_frames) = vui.max_num_reorder_frames { record.add("max_num_reorder_frames", max_num_reorder_frames); }
+    }
+    if let Some(frame_rate) = sps.frame_rate() { record.add("fps", frame_rate.timescale as f64 / frame_rate.sample_duration as f64); }
+}
+
+fn pps_fields(record: &mut Record, pps: &h264::Pps) {
+    record.add("pic_parameter_set_id", pps.pic_parameter_set_id);
+    record.add("seq_parameter_set_id", pps.seq_parameter_set_id);
+    record.add("entropy_coding_mode_flag", pps.entropy_coding_mode_flag);
+    record.add("bottom_field_pic_order_in_frame_present_flag", pps.bottom_field_pic_order_in_frame_present_flag);
+    record.add("num_slice_groups", pps.num_slice_groups_minus1 + 1);
+    record.add("num_ref_idx_l0_default_active", pps.num_ref_idx_l0_default_active_minus1 + 1);
+    record.add("num_ref_idx_l1_default_active", pps.num_ref_idx_l1_default_active_minus1 + 1);
+    record.add("weighted_pred_flag", pps.weighted_pred_flag);
+    record.add("weighted_bipred_idc", pps.weighted_bipred_idc);
+    record.add("pic_init_qp", pps.pic_init_qp_minus26 + 26);
+    record.add("deblocking_filter_control_present_flag", pps.deblocking_filter_control_present_flag);
+    record.add("transform_8x8_mode_flag", pps.transform_8x8_mode_flag);
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sei_message(message: &SeiMessage) -> Value {
+    let mut fields: Vec<(&'static str, Value)> = vec![
+        ("payload_type", message.payload_type.into()),
+        ("payload_size", message.payload_size.into()),
+    ];
+    match &message.payload {
+        SeiPayload::BufferingPeriod(bp) => {
+            fields.push(("name", "buffering_period".into()));
+            fields.push(("seq_parameter_set_id", bp.seq_parameter_set_id.into()));
+        }
+        SeiPayload::PicTiming(pt) => {
+            fields.push(("name", "pic_timing".into()));
+            if let Some(v) = pt.cpb_removal_delay { fields.push(("cpb_removal_delay", v.into())); }
+            if let Some(v) = pt.dpb_output_delay { fields.push(("dpb_output_delay", v.into())); }
+            if let Some(v) = pt.pic_struct { fields.push(("pic_struct", v.into())); }
+        }
+        SeiPayload::UserDataRegistered(ud) => {
+            fields.push(("name", "user_data_registered_itu_t_t35".into()));
+            fields.push(("itu_t_t35_country_code", ud.itu_t_t35_country_code.into()));
+        }
+        SeiPayload::UserDataUnregistered(ud) => {
+            fields.push(("name", "user_data_unregistered".into()));
+            fields.push(("uuid_iso_iec_11578", hex(&ud.uuid_iso_iec_11578).into()));
+        }
+        SeiPayload::RecoveryPoint(rp) => {
+            fields.push(("name", "recovery_point".into()));
+            fields.push(("recovery_frame_cnt", rp.recovery_frame_cnt.into()));
+            fields.push(("exact_match_flag", rp.exact_match_flag.into()));
+            fields.push(("broken_link_flag", rp.broken_link_flag.into()));
+            fields.push(("changing_slice_group_idc", rp.changing_slice_group_idc.into()));
+        }
+        SeiPayload::Other(payload) => fields.push(("payload", hex(payload).into())),
+    }
+    Value::Object(fields)
+}
+
+fn slice_fields(record: &mut Record, sh: &SliceHeader, poc: i32) {
+    record.add("first_mb_in_slice", sh.first_mb_in_slice);
+    record.add("slice_type", slice_type_name(SliceType::from_u32(sh.slice_type)));
+    record.add("pic_parameter_set_id", sh.pic_parameter_set_id);
+    record.add("frame_num", sh.frame_num);
+    if sh.field_pic_flag {
+        record.add("field_pic_flag", true);
+        record.add("bottom_field_flag", sh.bottom_field_flag);
+    }
+    if sh.idr_pic_flag { record.add("idr_pic_id", sh.idr_pic_id); }
+    record.add("pic_order_cnt_lsb", sh.pic_order_cnt_lsb);
+    record.add("poc", poc);
+}
+
+// Prints every NAL unit of a group of pictures followed by the group summary
+struct Analyzer<W: Write> {
+    out: W,
+    json: bool,
+    frame_rate: Option<FrameRate>,
+    sps_frame_rate: Option<FrameRate>, // of the most recent SPS
+    groups: usize,
+    frames: usize,
+    bytes: usize,
+    last_keyframe: Option<usize>,       // position of the last sync sample in the stream
+}
+
+impl<W: Write> Analyzer<W> {
+    fn new(out: W, json: bool, frame_rate: Option<FrameRate>) -> Analyzer<W> {
+        Analyzer{out, json, frame_rate, sps_frame_rate: None, groups: 0, frames: 0, bytes: 0, last_keyframe: None}
+    }
+
+    // the syntax was parsed while the access unit was built
+    fn nal(&mut self, nal: &NAL, syntax: &NalSyntax) {
+        let mut record = Record::new("nal");
+        record.add("offset", nal.start);
+        record.add("type", format!("{:?}", nal.unit_type));
+        record.add("nal_unit_type", nal.unit_type.to_u8());
+        record.add("ref_idc", nal.ref_idc);
+        record.add("size", nal.end - nal.start);
+        if nal.forbidden_zero_bit { record.add("forbidden_zero_bit", true); }
+        match syntax {
+            NalSyntax::Sps(sps) => {
+                self.sps_frame_rate = sps.frame_rate();
+                sps_fields(&mut record, sps);
+            }
+            NalSyntax::Pps(pps) => pps_fields(&mut record, pps),
+            NalSyntax::Sei(messages) => record.add("sei", Value::List(messages.iter().map(sei_message).collect())),
+            NalSyntax::Slice(sh, poc) => slice_fields(&mut record, sh, *poc),
+            NalSyntax::Other if matches!(nal.unit_type, NalUnitType::CodedSliceNonIdr | NalUnitType::CodedSliceIdr | NalUnitType::CodedSliceDataPartitionA) => {
+                record.add("error", "can't parse slice header")
+            }
+            NalSyntax::Other => {}
+        }
+        record.print(&mut self.out, self.json);
+    }
+
+    // user_data_unregistered payloads of an access unit, cameras tag their own metadata with a UUID
+    fn user_data(&mut self, au: &h264::AccessUnit) {
+        for ud in au.user_data_unregistered() {
+            let mut record = Record::new("user_data");
+            record.add("offset", au.nals.first().map_or(0, |nal| nal.start));
+            record.add("uuid", hex(&ud.uuid_iso_iec_11578));
+            record.add("size", ud.payload.len());
+            record.add("payload", hex(&ud.payload));
+            record.print(&mut self.out, self.json);
+        }
+    }
+
+    // frame count, picture types, bitrate of a group and the frames since the previous sync sample
+    fn group(&mut self, idr: &h264::IDR) {
+        let (mut i, mut p, mut b, mut bytes) = (0, 0, 0, 0);
+        for au in &idr.samples {
+            for (nal, syntax) in au.nals.iter().zip(&au.syntax) {
+                self.nal(nal, syntax);
+                bytes += nal.end - nal.start;
+            }
+            self.user_data(au);
+            match au.slice.as_ref().and_then(|sh| SliceType::from_u32(sh.slice_type)) {
+                Some(SliceType::I) | Some(SliceType::SI) => i += 1,
+                Some(SliceType::P) | Some(SliceType::SP) => p += 1,
+                Some(SliceType::B) => b += 1,
+                None => {}
+            }
+        }
+
+        let frame_rate = self.frame_rate.or(self.sps_frame_rate).unwrap_or(DEFAULT_FRAME_RATE);
+        let frames = idr.samples.len();
+        let duration = frames as f64 * frame_rate.sample_duration as f64 / frame_rate.timescale as f64;
+
+        let mut record = Record::new("gop");
+        record.add("index", self.groups);
+        record.add("offset", idr.samples[0].nals.first().map_or(0, |nal| nal.start));
+        let first = &idr.samples[0];
+        let recovery_point = first.recovery_point().filter(|_| !first.is_idr);
+        record.add("starts_with", if first.is_idr { "IDR" } else if recovery_point.is_some() { "recovery_point" } else { "none" });
+        if let Some(rp) = recovery_point {
+            record.add("recovery_frame_cnt", rp.recovery_frame_cnt);
+            record.add("exact_match_flag", rp.exact_match_flag);
+            record.add("broken_link_flag", rp.broken_link_flag);
+        }
+        record.add("frames", frames);
+        record.add("i", i);
+        record.add("p", p);
+        record.add("b", b);
+        record.add("bytes", bytes);
+        record.add("duration", duration);
+        record.add("bitrate_kbps", bytes as f64 * 8.0 / duration / 1000.0);
+        if first.is_random_access_point() {
+            if let Some(last_keyframe) = self.last_keyframe { record.add("keyframe_interval", self.frames - last_keyframe); }
+            self.last_keyframe = Some(self.frames);
+        }
+        record.print(&mut self.out, self.json);
+
+        self.groups += 1;
+        self.frames += frames;
+        self.bytes += bytes;
+    }
+}
+
+// Prints the NAL units of an H.264 stream with their decoded syntax elements and a summary per group of pictures
+pub fn analyze(path: &str, format: Option<h264::StreamFormat>, frame_rate: Option<FrameRate>, json: bool) -> Result<(), ()> {
+    let file = std::fs::File::open(path).map_err(|e| eprintln!("Can't open {}: {}", path, e))?;
+    let mut access_units = h264::AccessUnits::new(h264::NalReader::new(file, format));
+
+    let stdout = std::io::stdout();
+    let mut analyzer = Analyzer::new(stdout.lock(), json, frame_rate);
+    let mut gops = h264::GopBuilder::default();
+    for au in access_units.by_ref() {
+        if let Some(idr) = gops.push(au) { analyzer.group(&idr); }
+    }
+    if let Some(idr) = gops.finish() { analyzer.group(&idr); }
+
+    let mut record = Record::new("stream");
+    record.add("groups", analyzer.groups);
+    record.add("frames", analyzer.frames);
+    record.add("bytes", analyzer.bytes);
+    record.print(&mut analyzer.out, json);
+
+    if let Some(e) = access_units.nals_mut().take_error() {
+        eprintln!("Can't read {}: {}", path, e);
+        return Err(());
+    }
+    if analyzer.groups == 0 { return Err(()); }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::STREAM;
+
+    fn access_units() -> h264::AccessUnits<h264::NalReader<std::fs::File>> {
+        h264::AccessUnits::new(h264::NalReader::new(std::fs::File::open(STREAM).unwrap(), None))
+    }
+
+    fn lines(out: Vec<u8>) -> Vec<String> {
+        String::from_utf8(out).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn nal_record() {
+        let au = access_units().next().unwrap();
+        let mut analyzer = Analyzer::new(vec![], true, None);
+        analyzer.nal(&au.nals[0], &au.syntax[0]);
+        analyzer.json = false;
+        analyzer.nal(&au.nals[2], &au.syntax[2]);
+        assert_eq!(lines(analyzer.out), [
+            concat!(
+                r#"{"kind":"nal","offset":4,"type":"SPS","nal_unit_type":7,"ref_idc":3,"size":16,"seq_parameter_set_id":0,"#,
+                r#""profile_idc":66,"constraint_flags":0,"level_idc":42,"chroma_format_idc":1,"bit_depth_luma":8,"bit_depth_chroma":8,"#,
+                r#""log2_max_frame_num":10,"pic_order_cnt_type":2,"max_num_ref_frames":1,"frame_mbs_only_flag":true,"#,
+                r#""mb_adaptive_frame_field_flag":false,"width":1920,"height":1080}"#,
+            ),
+            "nal offset=32 type=SEI nal_unit_type=6 ref_idc=0 size=5 sei=[payload_type=229 payload_size=1 payload=e9]",
+        ]);
+    }
+
+    #[test]
+    fn gop_record() {
+        let mut analyzer = Analyzer::new(vec![], false, None);
+        let mut gops = h264::GopBuilder::default();
+        for au in access_units() {
+            if let Some(idr) = gops.push(au) {
+                analyzer.group(&idr);
+                if analyzer.groups == 2 { break; }
+            }
+        }
+        let gops: Vec<String> = lines(analyzer.out).into_iter().filter(|line| line.starts_with("gop ")).collect();
+        assert_eq!(gops, [
+            "gop index=0 offset=4 starts_with=IDR frames=30 i=1 p=29 b=0 bytes=447736 duration=1.000 bitrate_kbps=3581.888",
+            "gop index=1 offset=447872 starts_with=IDR frames=30 i=1 p=29 b=0 bytes=467101 duration=1.000 bitrate_kbps=3736.808 keyframe_interval=30",
+        ]);
+    }
+
+    #[test]
+    fn non_finite_float() {
+        let mut record = Record::new("gop");
+        record.add("bitrate_kbps", f64::INFINITY);
+        record.add("duration", f64::NAN);
+        assert_eq!(record.line(true), r#"{"kind":"gop","bitrate_kbps":null,"duration":null}"#);
+    }
+}
@@ -0,0 +1,610 @@
+use std::io::{BufRead, BufReader, Read};
+
+use crate::h264::FrameRate;
+use crate::rbsp::BitReader;
+
+// Group of temporal units starting at a key frame
+pub struct Gop {
+    pub samples: Vec<TemporalUnit>,
+}
+
+// 7.5 Ordering of OBUs: all the OBUs of one presentation time, starting with a temporal delimiter
+#[derive(Debug, Clone, Default)]
+pub struct TemporalUnit {
+    pub obus: Vec<Obu>,
+    pub is_key: bool,       // the first frame is a shown key frame
+}
+
+impl TemporalUnit {
+    // AV1-ISOBMFF 2.4: the sample is the temporal unit without temporal delimiter and padding OBUs,
+    // every OBU with its obu_size field
+    pub fn sample(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for obu in &self.obus {
+            if !matches!(obu.obu_type, ObuType::TemporalDelimiter | ObuType::Padding) {
+                obu.write(&mut data);
+            }
+        }
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObuType { // 6.2.2 OBU header semantics
+    SequenceHeader,         // 1
+    TemporalDelimiter,      // 2
+    FrameHeader,            // 3
+    TileGroup,              // 4
+    Metadata,               // 5
+    Frame,                  // 6
+    RedundantFrameHeader,   // 7
+    TileList,               // 8
+    Padding,                // 15
+    Reserved(u8),           // 0, 9..14
+}
+
+impl ObuType {
+    pub fn from_u8(v: u8) -> ObuType {
+        match v {
+            1  => ObuType::SequenceHeader,
+            2  => ObuType::TemporalDelimiter,
+            3  => ObuType::FrameHeader,
+            4  => ObuType::TileGroup,
+            5  => ObuType::Metadata,
+            6  => ObuType::Frame,
+            7  => ObuType::RedundantFrameHeader,
+            8  => ObuType::TileList,
+            15 => ObuType::Padding,
+            _  => ObuType::Reserved(v),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ObuType::SequenceHeader => 1,
+            ObuType::TemporalDelimiter => 2,
+            ObuType::FrameHeader => 3,
+            ObuType::TileGroup => 4,
+            ObuType::Metadata => 5,
+            ObuType::Frame => 6,
+            ObuType::RedundantFrameHeader => 7,
+            ObuType::TileList => 8,
+            ObuType::Padding => 15,
+            ObuType::Reserved(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Obu {
+    pub start: usize,               // position of the OBU header in the file
+    pub obu_type: ObuType,
+    pub extension: Option<u8>,      // temporal_id, spatial_id and reserved bits
+    pub payload: Vec<u8>,
+}
+
+impl Obu {
+    // 5.3.1 General OBU syntax. sz is obu_length of the Annex B format, None in the low overhead format
+    // where every OBU has an obu_size field. Returns the OBU and the number of bytes it takes.
+    pub fn parse(data: &[u8], start: usize, sz: Option<usize>) -> Option<(Obu, usize)> {
+        let header = *data.first()?;
+        if header & 0x80 != 0 { return None; } // obu_forbidden_bit
+        let obu_type = ObuType::from_u8((header >> 3) & 0x0F);
+        let obu_extension_flag = header & 0x04 != 0;
+        let obu_has_size_field = header & 0x02 != 0;
+        let mut pos = 1;
+        let mut extension = None;
+        if obu_extension_flag {
+            extension = Some(*data.get(1)?);
+            pos += 1;
+        }
+        let obu_size = if obu_has_size_field {
+            let (obu_size, n) = read_leb128(data.get(pos ..)?)?;
+            pos += n;
+            obu_size as usize
+        } else {
+            sz?.checked_sub(pos)?
+        };
+        let payload = data.get(pos .. pos + obu_size)?.to_vec();
+        let total = match sz { Some(sz) => sz, None => pos + obu_size };
+        Some((Obu{start, obu_type, extension, payload}, total))
+    }
+
+    // low overhead bitstream format, with obu_has_size_field = 1
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let extension_flag = if self.extension.is_some() { 0x04 } else { 0 };
+        out.push((self.obu_type.to_u8() << 3) | extension_flag | 0x02);
+        out.extend(self.extension);
+        write_leb128(out, self.payload.len() as u64);
+        out.extend_from_slice(&self.payload);
+    }
+}
+
+// 4.10.5 leb128(), returns the value and its length in bytes
+pub fn read_leb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for i in 0 .. 8 {
+        let b = *data.get(i)?;
+        value |= ((b & 0x7F) as u64) << (7 * i);
+        if b & 0x80 == 0 { return Some((value, i + 1)); }
+    }
+    None
+}
+
+pub fn write_leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let b = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 { out.push(b); break; }
+        out.push(b | 0x80);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SequenceHeader { // 5.5 Sequence header OBU syntax, up to color_config()
+    pub seq_profile: u8,
+    pub still_picture: bool,
+    pub reduced_still_picture_header: bool,
+    pub timing_info_present_flag: bool,
+    pub num_units_in_display_tick: u32,
+    pub time_scale: u32,
+    pub equal_picture_interval: bool,
+    pub num_ticks_per_picture_minus_1: u32,
+    pub initial_display_delay_present_flag: bool,
+    pub initial_display_delay_minus_1: Option<u8>,  // of the first operating point
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    pub max_frame_width_minus_1: u32,
+    pub max_frame_height_minus_1: u32,
+    // color_config()
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub mono_chrome: bool,
+    pub color_range: bool,
+    pub subsampling_x: bool,
+    pub subsampling_y: bool,
+    pub chroma_sample_position: u8,
+}
+
+impl SequenceHeader {
+    pub fn parse(payload: &[u8]) -> Option<SequenceHeader> {
+        let mut sh = SequenceHeader::default();
+        let mut r = BitReader::new(payload);
+        sh.seq_profile = r.read_bits(3)? as u8;
+        sh.still_picture = r.read_bit()?;
+        sh.reduced_still_picture_header = r.read_bit()?;
+        if sh.reduced_still_picture_header {
+            sh.seq_level_idx_0 = r.read_bits(5)? as u8;
+        } else {
+            sh.timing_info_present_flag = r.read_bit()?;
+            let mut decoder_model_info_present_flag = false;
+            let mut buffer_delay_length_minus_1 = 0;
+            if sh.timing_info_present_flag {
+                sh.num_units_in_display_tick = r.read_bits(32)?;
+                sh.time_scale = r.read_bits(32)?;
+                sh.equal_picture_interval = r.read_bit()?;
+                if sh.equal_picture_interval {
+                    sh.num_ticks_per_picture_minus_1 = r.read_ue()?; // uvlc()
+                }
+                decoder_model_info_present_flag = r.read_bit()?;
+                if decoder_model_info_present_flag {
+                    buffer_delay_length_minus_1 = r.read_bits(5)? as usize;
+                    r.read_bits(32)?; // num_units_in_decoding_tick
+                    r.read_bits(5)?; // buffer_removal_time_length_minus_1
+                    r.read_bits(5)?; // frame_presentation_time_length_minus_1
+                }
+            }
+            sh.initial_display_delay_present_flag = r.read_bit()?;
+            let operating_points_cnt_minus_1 = r.read_bits(5)?;
+            for i in 0 ..= operating_points_cnt_minus_1 {
+                r.read_bits(12)?; // operating_point_idc
+                let seq_level_idx = r.read_bits(5)? as u8;
+                let seq_tier = seq_level_idx > 7 && r.read_bit()?;
+                if i == 0 {
+                    sh.seq_level_idx_0 = seq_level_idx;
+                    sh.seq_tier_0 = seq_tier;
+                }
+                if decoder_model_info_present_flag && r.read_bit()? { // decoder_model_present_for_this_op
+                    r.skip_bits(2 * (buffer_delay_length_minus_1 + 1))?; // decoder_buffer_delay, encoder_buffer_delay
+                    r.read_bit()?; // low_delay_mode_flag
+                }
+                if sh.initial_display_delay_present_flag && r.read_bit()? { // initial_display_delay_present_for_this_op
+                    let initial_display_delay_minus_1 = r.read_bits(4)? as u8;
+                    if i == 0 { sh.initial_display_delay_minus_1 = Some(initial_display_delay_minus_1); }
+                }
+            }
+        }
+        let frame_width_bits_minus_1 = r.read_bits(4)? as u8;
+        let frame_height_bits_minus_1 = r.read_bits(4)? as u8;
+        sh.max_frame_width_minus_1 = r.read_bits(frame_width_bits_minus_1 + 1)?;
+        sh.max_frame_height_minus_1 = r.read_bits(frame_height_bits_minus_1 + 1)?;
+        if !sh.reduced_still_picture_header && r.read_bit()? { // frame_id_numbers_present_flag
+            r.read_bits(4)?; // delta_frame_id_length_minus_2
+            r.read_bits(3)?; // additional_frame_id_length_minus_1
+        }
+        r.read_bit()?; // use_128x128_superblock
+        r.read_bit()?; // enable_filter_intra
+        r.read_bit()?; // enable_intra_edge_filter
+        if !sh.reduced_still_picture_header {
+            r.read_bit()?; // enable_interintra_compound
+            r.read_bit()?; // enable_masked_compound
+            r.read_bit()?; // enable_warped_motion
+            r.read_bit()?; // enable_dual_filter
+            let enable_order_hint = r.read_bit()?;
+            if enable_order_hint {
+                r.read_bit()?; // enable_jnt_comp
+                r.read_bit()?; // enable_ref_frame_mvs
+            }
+            let seq_choose_screen_content_tools = r.read_bit()?;
+            let seq_force_screen_content_tools = if seq_choose_screen_content_tools { 2 } else { r.read_bits(1)? };
+            if seq_force_screen_content_tools > 0 && !r.read_bit()? { // seq_choose_integer_mv
+                r.read_bit()?; // seq_force_integer_mv
+            }
+            if enable_order_hint {
+                r.read_bits(3)?; // order_hint_bits_minus_1
+            }
+        }
+        r.read_bit()?; // enable_superres
+        r.read_bit()?; // enable_cdef
+        r.read_bit()?; // enable_restoration
+        sh.parse_color_config(&mut r)?;
+        Some(sh)
+    }
+
+    // 5.5.2 Color config syntax
+    fn parse_color_config(&mut self, r: &mut BitReader) -> Option<()> {
+        self.high_bitdepth = r.read_bit()?;
+        if self.seq_profile == 2 && self.high_bitdepth {
+            self.twelve_bit = r.read_bit()?;
+        }
+        self.mono_chrome = self.seq_profile != 1 && r.read_bit()?;
+        let color_description_present_flag = r.read_bit()?;
+        let (mut color_primaries, mut transfer_characteristics, mut matrix_coefficients) = (2, 2, 2); // CP_UNSPECIFIED, ...
+        if color_description_present_flag {
+            color_primaries = r.read_bits(8)?;
+            transfer_characteristics = r.read_bits(8)?;
+            matrix_coefficients = r.read_bits(8)?;
+        }
+        if self.mono_chrome {
+            self.color_range = r.read_bit()?;
+            self.subsampling_x = true;
+            self.subsampling_y = true;
+            return Some(());
+        }
+        if color_primaries == 1 && transfer_characteristics == 13 && matrix_coefficients == 0 { // BT.709 primaries, sRGB, identity
+            self.color_range = true;
+            return Some(());
+        }
+        self.color_range = r.read_bit()?;
+        match self.seq_profile {
+            0 => { self.subsampling_x = true; self.subsampling_y = true; }
+            1 => {}
+            _ => {
+                if self.twelve_bit {
+                    self.subsampling_x = r.read_bit()?;
+                    self.subsampling_y = self.subsampling_x && r.read_bit()?;
+                } else {
+                    self.subsampling_x = true;
+                }
+            }
+        }
+        if self.subsampling_x && self.subsampling_y {
+            self.chroma_sample_position = r.read_bits(2)? as u8;
+        }
+        Some(())
+    }
+
+    pub fn width(&self) -> u32 { self.max_frame_width_minus_1 + 1 }
+    pub fn height(&self) -> u32 { self.max_frame_height_minus_1 + 1 }
+
+    pub fn frame_rate(&self) -> Option<FrameRate> {
+        if !self.timing_info_present_flag || !self.equal_picture_interval { return None; }
+        if self.num_units_in_display_tick == 0 || self.time_scale == 0 { return None; }
+        let ticks_per_picture = self.num_ticks_per_picture_minus_1.checked_add(1)?;
+        Some(FrameRate{timescale: self.time_scale, sample_duration: self.num_units_in_display_tick.checked_mul(ticks_per_picture)?})
+    }
+
+    // true when the frame header OBU payload starts a shown key frame (5.9.2 uncompressed_header)
+    pub fn is_key_frame(&self, frame_header: &[u8]) -> bool {
+        if self.reduced_still_picture_header { return true; }
+        let mut r = BitReader::new(frame_header);
+        let show_existing_frame = r.read_bit();
+        let frame_type = r.read_bits(2);
+        let show_frame = r.read_bit();
+        show_existing_frame == Some(false) && frame_type == Some(0) && show_frame == Some(true) // KEY_FRAME
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Av1Format {
+    Ivf,        // IVF container with one temporal unit per frame
+    Section5,   // low overhead bitstream format, OBUs with size fields
+    AnnexB,     // Annex B length delimited bitstream format
+}
+
+impl Av1Format {
+    pub fn detect(data: &[u8]) -> Av1Format {
+        if data.starts_with(b"DKIF") { return Av1Format::Ivf; }
+        // a temporal delimiter OBU with obu_has_size_field and obu_size 0
+        if data.starts_with(&[0x12, 0x00]) { return Av1Format::Section5; }
+        Av1Format::AnnexB
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// OBUs in the low overhead format, start is the position of data in the file
+fn parse_obus(data: &[u8], start: usize) -> std::io::Result<Vec<Obu>> {
+    let mut obus = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let (obu, size) = Obu::parse(&data[pos ..], start + pos, None).ok_or_else(|| invalid_data(format!("can't parse OBU at {}", start + pos)))?;
+        obus.push(obu);
+        pos += size;
+    }
+    Ok(obus)
+}
+
+// B.2 Length delimited bitstream syntax, the frame units of one temporal unit. start is the position
+// of data in the file.
+fn parse_annexb_unit(data: &[u8], start: usize) -> std::io::Result<TemporalUnit> {
+    let truncated = |pos: usize| invalid_data(format!("truncated frame unit at {}", start + pos));
+    let mut unit = TemporalUnit::default();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (frame_unit_size, n) = read_leb128(&data[pos ..]).ok_or_else(|| truncated(pos))?;
+        pos += n;
+        let frame_end = pos + frame_unit_size as usize;
+        if frame_end > data.len() { return Err(invalid_data(format!("frame unit at {} exceeds its temporal unit", start + pos))); }
+        while pos < frame_end {
+            let (obu_length, n) = read_leb128(&data[pos .. frame_end]).ok_or_else(|| truncated(pos))?;
+            pos += n;
+            let (obu, size) = Obu::parse(&data[pos .. frame_end], start + pos, Some(obu_length as usize))
+                .ok_or_else(|| invalid_data(format!("can't parse OBU at {}", start + pos)))?;
+            unit.obus.push(obu);
+            pos += size;
+        }
+    }
+    Ok(unit)
+}
+
+// Temporal units of an IVF file or an OBU stream, read one at a time
+pub struct TemporalUnitReader<R: Read> {
+    reader: BufReader<R>,
+    format: Av1Format,
+    position: usize,                // of the next byte in the file
+    done: bool,                     // the stream can't be parsed further
+    error: Option<std::io::Error>,  // why the temporal units ended before the end of the stream
+    next: Option<Obu>,              // Section 5: temporal delimiter of the next temporal unit
+    time_base: Option<(u32, u32)>,  // IVF: time base denominator and numerator
+    first_pts: Option<u64>,
+    frame_duration: Option<u32>,    // IVF: difference of the first two timestamps
+}
+
+impl<R: Read> TemporalUnitReader<R> {
+    pub fn new(reader: R) -> std::io::Result<TemporalUnitReader<R>> {
+        let mut reader = BufReader::new(reader);
+        let format = Av1Format::detect(reader.fill_buf()?);
+        let mut units = TemporalUnitReader{
+            reader, format, position: 0, done: false, error: None, next: None, time_base: None, first_pts: None, frame_duration: None,
+        };
+        if format == Av1Format::Ivf {
+            let header = units.read_bytes(32, "IVF header")?;
+            let le32 = |pos: usize| u32::from_le_bytes([header[pos], header[pos + 1], header[pos + 2], header[pos + 3]]);
+            units.time_base = Some((le32(16), le32(20)));
+            let header_size = u16::from_le_bytes([header[6], header[7]]) as usize;
+            units.read_bytes(header_size.saturating_sub(32), "IVF header")?;
+        }
+        Ok(units)
+    }
+
+    // a read error or a truncated or unparsable temporal unit ends the temporal units like the end
+    // of the stream, the caller has to check for it
+    pub fn take_error(&mut self) -> Option<std::io::Error> { self.error.take() }
+
+    // the time base of the IVF header times the duration of the first frame
+    pub fn ivf_frame_rate(&self) -> Option<FrameRate> {
+        let (rate, scale) = self.time_base?;
+        if rate == 0 || scale == 0 { return None; }
+        Some(FrameRate{timescale: rate, sample_duration: scale.checked_mul(self.frame_duration.unwrap_or(1))?})
+    }
+
+    // temporal units end at the end of the stream, anywhere else it truncates them
+    fn at_end(&mut self) -> std::io::Result<bool> {
+        Ok(self.reader.fill_buf()?.is_empty())
+    }
+
+    fn read_bytes(&mut self, n: usize, what: &str) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![];
+        match (&mut self.reader).take(n as u64).read_to_end(&mut data) {
+            Ok(read) if read == n => { self.position += n; Ok(data) }
+            Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("truncated {} at {}", what, self.position))),
+            Err(e) => Err(std::io::Error::new(e.kind(), format!("can't read {} at {}: {}", what, self.position, e))),
+        }
+    }
+
+    // 4.10.5 leb128(), its bytes are appended to data
+    fn read_leb128(&mut self, data: &mut Vec<u8>) -> std::io::Result<u64> {
+        let start = data.len();
+        loop {
+            let b = self.read_bytes(1, "leb128")?[0];
+            data.push(b);
+            if b & 0x80 == 0 || data.len() - start == 8 { break; }
+        }
+        read_leb128(&data[start ..]).map(|(value, _)| value).ok_or_else(|| invalid_data(format!("invalid leb128 at {}", self.position)))
+    }
+
+    // an OBU of the low overhead format
+    fn read_obu(&mut self) -> std::io::Result<Obu> {
+        let start = self.position;
+        let mut data = self.read_bytes(1, "OBU")?;
+        if data[0] & 0x04 != 0 { data.extend(self.read_bytes(1, "OBU")?); } // obu_extension_flag
+        let obu = if data[0] & 0x02 != 0 { // obu_has_size_field
+            let obu_size = self.read_leb128(&mut data)?;
+            data.extend(self.read_bytes(obu_size as usize, "OBU")?);
+            Obu::parse(&data, start, None).map(|(obu, _)| obu)
+        } else {
+            None
+        };
+        obu.ok_or_else(|| invalid_data(format!("can't parse OBU at {}", start)))
+    }
+
+    fn read_ivf_frame(&mut self) -> std::io::Result<Option<TemporalUnit>> {
+        if self.at_end()? { return Ok(None); }
+        let header = self.read_bytes(12, "IVF frame header")?;
+        let frame_size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let pts = header[4 .. 12].iter().rev().fold(0u64, |v, b| (v << 8) | *b as u64);
+        let start = self.position;
+        let frame = self.read_bytes(frame_size, "IVF frame")?;
+        match self.first_pts {
+            None => self.first_pts = Some(pts),
+            Some(first) if self.frame_duration.is_none() => self.frame_duration = Some(if pts > first { (pts - first) as u32 } else { 1 }),
+            Some(_) => {}
+        }
+        Ok(Some(TemporalUnit{obus: parse_obus(&frame, start)?, is_key: false}))
+    }
+
+    // Section 5 streams are split into temporal units at temporal delimiters
+    fn read_section5_unit(&mut self) -> std::io::Result<Option<TemporalUnit>> {
+        let mut unit = TemporalUnit::default();
+        unit.obus.extend(self.next.take());
+        while !self.at_end()? {
+            let obu = self.read_obu()?;
+            if obu.obu_type == ObuType::TemporalDelimiter && !unit.obus.is_empty() {
+                self.next = Some(obu);
+                break;
+            }
+            unit.obus.push(obu);
+        }
+        Ok(if unit.obus.is_empty() { None } else { Some(unit) })
+    }
+
+    fn read_annexb_unit(&mut self) -> std::io::Result<Option<TemporalUnit>> {
+        if self.at_end()? { return Ok(None); }
+        let temporal_unit_size = self.read_leb128(&mut vec![])?;
+        let start = self.position;
+        let data = self.read_bytes(temporal_unit_size as usize, "temporal unit")?;
+        parse_annexb_unit(&data, start).map(Some)
+    }
+}
+
+impl<R: Read> Iterator for TemporalUnitReader<R> {
+    type Item = TemporalUnit;
+
+    fn next(&mut self) -> Option<TemporalUnit> {
+        if self.done { return None; }
+        let unit = match self.format {
+            Av1Format::Ivf => self.read_ivf_frame(),
+            Av1Format::Section5 => self.read_section5_unit(),
+            Av1Format::AnnexB => self.read_annexb_unit(),
+        };
+        match unit {
+            Ok(Some(unit)) => Some(unit),
+            Ok(None) => { self.done = true; None }
+            Err(e) => { self.error = Some(e); self.done = true; None }
+        }
+    }
+}
+
+// Groups of pictures of a stream, read one at a time so only the temporal units of the returned group
+// are kept in memory
+pub struct GopReader<R: Read> {
+    units: TemporalUnitReader<R>,
+    sequence_header: Option<(Obu, SequenceHeader)>, // the first one of the stream
+    next: Option<TemporalUnit>,                     // key frame starting the next group
+}
+
+impl GopReader<std::fs::File> {
+    pub fn open(path: &str) -> std::io::Result<GopReader<std::fs::File>> {
+        GopReader::new(std::fs::File::open(path)?)
+    }
+}
+
+impl<R: Read> GopReader<R> {
+    pub fn new(reader: R) -> std::io::Result<GopReader<R>> {
+        Ok(GopReader{units: TemporalUnitReader::new(reader)?, sequence_header: None, next: None})
+    }
+
+    // the first sequence header OBU, known once the first group is read
+    pub fn sequence_header(&self) -> Option<&(Obu, SequenceHeader)> { self.sequence_header.as_ref() }
+
+    pub fn ivf_frame_rate(&self) -> Option<FrameRate> { self.units.ivf_frame_rate() }
+
+    // the read or parse error that ended the groups early
+    pub fn take_error(&mut self) -> Option<std::io::Error> { self.units.take_error() }
+
+    fn inspect(&mut self, unit: &mut TemporalUnit) {
+        for obu in &unit.obus {
+            if obu.obu_type == ObuType::SequenceHeader {
+                match SequenceHeader::parse(&obu.payload) {
+                    Some(parsed) => if self.sequence_header.is_none() { self.sequence_header = Some((obu.clone(), parsed)); },
+                    None => eprintln!("Can't parse sequence header at {}", obu.start),
+                }
+            }
+        }
+        let frame_header = unit.obus.iter().find(|obu| matches!(obu.obu_type, ObuType::FrameHeader | ObuType::Frame));
+        if let (Some((_, parsed)), Some(frame_header)) = (&self.sequence_header, frame_header) {
+            unit.is_key = parsed.is_key_frame(&frame_header.payload);
+        }
+    }
+}
+
+impl<R: Read> Iterator for GopReader<R> {
+    type Item = Gop;
+
+    fn next(&mut self) -> Option<Gop> {
+        let mut samples: Vec<TemporalUnit> = self.next.take().into_iter().collect();
+        while let Some(mut unit) = self.units.next() {
+            self.inspect(&mut unit);
+            if unit.is_key && !samples.is_empty() {
+                self.next = Some(unit);
+                return Some(Gop{samples});
+            }
+            samples.push(unit);
+        }
+        if samples.is_empty() { None } else { Some(Gop{samples}) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IVF file of 30 frames per second with the given frames
+    fn ivf(frames: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut data = b"DKIF".to_vec();
+        data.extend([0, 0, 32, 0]);
+        data.extend(b"AV01");
+        data.extend([0; 4]); // width, height
+        data.extend(30u32.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend((frames.len() as u32).to_le_bytes());
+        data.extend([0; 4]);
+        for (i, (frame_size, frame)) in frames.iter().enumerate() {
+            data.extend(frame_size.to_le_bytes());
+            data.extend((i as u64).to_le_bytes());
+            data.extend(*frame);
+        }
+        data
+    }
+
+    #[test]
+    fn truncated_ivf_frame() {
+        let temporal_delimiter: &[u8] = &[0x12, 0x00];
+        let mut units = TemporalUnitReader::new(std::io::Cursor::new(ivf(&[(2, temporal_delimiter)]))).unwrap();
+        assert_eq!(units.by_ref().count(), 1);
+        assert!(units.take_error().is_none());
+
+        // the second frame has 2 of its 10 bytes
+        let mut units = TemporalUnitReader::new(std::io::Cursor::new(ivf(&[(2, temporal_delimiter), (10, temporal_delimiter)]))).unwrap();
+        assert_eq!(units.by_ref().count(), 1);
+        assert_eq!(units.take_error().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // an OBU longer than its frame
+        let mut units = TemporalUnitReader::new(std::io::Cursor::new(ivf(&[(2, &[0x12, 0x05])]))).unwrap();
+        assert_eq!(units.by_ref().count(), 0);
+        assert_eq!(units.take_error().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
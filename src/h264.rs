@@ -1,209 +1,2156 @@
 use std::io::prelude::*;
-use std::io::Cursor;
-use bytes::{IntoBuf, BytesMut, BufMut, Buf};
+use std::collections::HashMap;
+use bytes::{Bytes, BytesMut};
+
+use crate::rbsp::{BitReader, ebsp_to_rbsp};
 
 // h264_iso-iec_14496-10.pdf
 
+// Group of access units starting with a random access point (IDR picture or recovery point)
+#[allow(clippy::upper_case_acronyms)] // the names of the standard
 pub struct IDR {
-    pub samples : Vec<(NalUnitType,Vec<u8>)>
+    pub samples : Vec<AccessUnit>,
+    // SPS and PPS the group's pictures refer to
+    pub sps: Option<NAL>,
+    pub pps: Option<NAL>,
+    pub sps_ext: Option<NAL>,   // extension of the SPS, e.g. for auxiliary coded pictures
+}
+
+// Syntax of a NAL unit, parsed once while its access unit is built
+#[derive(Debug, Clone)]
+pub enum NalSyntax {
+    Sps(Sps),
+    Pps(Pps),
+    Sei(Vec<SeiMessage>),
+    Slice(SliceHeader, i32), // with the PicOrderCnt() of its picture
+    Other,                   // not parsed or can't be parsed
 }
 
-#[repr(u8)]
-#[derive(Debug, Clone, PartialEq)]
+// 7.4.1.2 Order of NAL units and association to coded pictures, access units, and video sequences
+#[derive(Debug, Clone, Default)]
+pub struct AccessUnit {
+    pub nals: Vec<NAL>,
+    pub is_idr: bool,               // the primary coded picture is an IDR picture
+    pub is_reference: bool,         // nal_ref_idc != 0 for the primary coded picture
+    pub syntax: Vec<NalSyntax>,     // of every NAL unit, in the order of nals
+    pub slice: Option<SliceHeader>, // header of the first slice of the primary coded picture
+    pub poc: i32,                   // PicOrderCnt() of the primary coded picture
+    pub is_damaged: bool,           // a slice header can't be parsed or a NAL unit was dropped by the resilient mode
+    pub index: usize,               // position in the stream, counting the access units the resilient mode drops
+}
+
+impl AccessUnit {
+    // IDR pictures and pictures with a recovery point SEI message, intra refresh cameras never send IDR pictures
+    pub fn is_random_access_point(&self) -> bool { self.is_idr || self.recovery_point().is_some() }
+
+    pub fn recovery_point(&self) -> Option<&RecoveryPoint> {
+        self.sei().find_map(|message| match &message.payload {
+            SeiPayload::RecoveryPoint(rp) => Some(rp),
+            _ => None
+        })
+    }
+
+    // the primary coded picture is a single field (field_pic_flag)
+    pub fn is_field(&self) -> bool { self.slice.as_ref().is_some_and(|slice| slice.field_pic_flag) }
+
+    // 3.30 complementary field pair: opposite parity and same frame_num, following the first field in
+    // decoding order. An IDR field starts a new picture, it can't be the second field of a pair.
+    pub fn is_second_field_of(&self, first: &AccessUnit) -> bool {
+        match (&first.slice, &self.slice) {
+            (Some(first), Some(second)) => {
+                first.field_pic_flag && second.field_pic_flag && first.bottom_field_flag != second.bottom_field_flag &&
+                first.frame_num == second.frame_num && !second.idr_pic_flag
+            }
+            _ => false,
+        }
+    }
+
+    // appends the second field, both fields are stored in one sample
+    pub fn merge_field(&mut self, second: AccessUnit) {
+        self.nals.extend(second.nals);
+        self.is_reference |= second.is_reference;
+        self.syntax.extend(second.syntax);
+        self.poc = self.poc.min(second.poc); // 8.2.1 PicOrderCnt of a complementary field pair
+    }
+
+    // messages of all SEI NAL units of the access unit
+    pub fn sei(&self) -> impl Iterator<Item = &SeiMessage> {
+        self.syntax.iter().flat_map(|syntax| match syntax {
+            NalSyntax::Sei(messages) => &messages[..],
+            _ => &[],
+        })
+    }
+
+    // user_data_unregistered payloads, cameras put their own UUID tagged metadata there
+    pub fn user_data_unregistered(&self) -> impl Iterator<Item = &UserDataUnregistered> {
+        self.sei().filter_map(|message| match &message.payload {
+            SeiPayload::UserDataUnregistered(ud) => Some(ud),
+            _ => None
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
 pub enum NalUnitType { //   Table 7-1 NAL unit type codes
-    Unspecified = 0,                // Unspecified
-    CodedSliceNonIdr = 1,           // Coded slice of a non-IDR picture
-    CodedSliceDataPartitionA = 2,   // Coded slice data partition A
-    CodedSliceDataPartitionB = 3,   // Coded slice data partition B
-    CodedSliceDataPartitionC = 4,   // Coded slice data partition C
-    CodedSliceIdr = 5,              // Coded slice of an IDR picture
-    SEI = 6,                        // Supplemental enhancement information (SEI)
-    SPS = 7,                        // Sequence parameter set
-    PPS = 8,                        // Picture parameter set
-    AUD = 9,                        // Access unit delimiter
-    EndOfSequence = 10,             // End of sequence
-    EndOfStream = 11,               // End of stream
-    Filler = 12,                    // Filler data
-    SpsExt = 13,                    // Sequence parameter set extension
-                // 14..18           // Reserved
-    NalUnitTypeCodedSliceAux = 19,  // Coded slice of an auxiliary coded picture without partitioning
-                // 20..23           // Reserved
+    Unspecified,                    // 0 Unspecified
+    CodedSliceNonIdr,               // 1 Coded slice of a non-IDR picture
+    CodedSliceDataPartitionA,       // 2 Coded slice data partition A
+    CodedSliceDataPartitionB,       // 3 Coded slice data partition B
+    CodedSliceDataPartitionC,       // 4 Coded slice data partition C
+    CodedSliceIdr,                  // 5 Coded slice of an IDR picture
+    SEI,                            // 6 Supplemental enhancement information (SEI)
+    SPS,                            // 7 Sequence parameter set
+    PPS,                            // 8 Picture parameter set
+    AUD,                            // 9 Access unit delimiter
+    EndOfSequence,                  // 10 End of sequence
+    EndOfStream,                    // 11 End of stream
+    Filler,                         // 12 Filler data
+    SpsExt,                         // 13 Sequence parameter set extension
+    PrefixNal,                      // 14 Prefix NAL unit (Annex G, H)
+    SubsetSps,                      // 15 Subset sequence parameter set (Annex G, H, I, J)
+    DepthParameterSet,              // 16 Depth parameter set (Annex J)
+                // 17..18           // Reserved
+    NalUnitTypeCodedSliceAux,       // 19 Coded slice of an auxiliary coded picture without partitioning
+    CodedSliceExtension,            // 20 Coded slice extension (SVC, MVC)
+    CodedSliceExtensionDepth,       // 21 Coded slice extension for a depth view component or a 3D-AVC texture view component
+                // 22..23           // Reserved
                 // 24..31           // Unspecified
+    Other(u8),                      // reserved and unspecified values
 }
 
 impl NalUnitType {
-    pub fn from_u8(v: u8) -> Option<NalUnitType>  {
+    pub fn from_u8(v: u8) -> NalUnitType  {
         match v {
-            0  => Some(NalUnitType::Unspecified),
-            1  => Some(NalUnitType::CodedSliceNonIdr),
-            2  => Some(NalUnitType::CodedSliceDataPartitionA),
-            3  => Some(NalUnitType::CodedSliceDataPartitionB),
-            4  => Some(NalUnitType::CodedSliceDataPartitionC),
-            5  => Some(NalUnitType::CodedSliceIdr),
-            6  => Some(NalUnitType::SEI),
-            7  => Some(NalUnitType::SPS),
-            8  => Some(NalUnitType::PPS),
-            9  => Some(NalUnitType::AUD),
-            10 => Some(NalUnitType::EndOfSequence),
-            11 => Some(NalUnitType::EndOfStream),
-            12 => Some(NalUnitType::Filler),
-            13 => Some(NalUnitType::SpsExt),
-            19 => Some(NalUnitType::NalUnitTypeCodedSliceAux),
-            _  => None
+            0  => NalUnitType::Unspecified,
+            1  => NalUnitType::CodedSliceNonIdr,
+            2  => NalUnitType::CodedSliceDataPartitionA,
+            3  => NalUnitType::CodedSliceDataPartitionB,
+            4  => NalUnitType::CodedSliceDataPartitionC,
+            5  => NalUnitType::CodedSliceIdr,
+            6  => NalUnitType::SEI,
+            7  => NalUnitType::SPS,
+            8  => NalUnitType::PPS,
+            9  => NalUnitType::AUD,
+            10 => NalUnitType::EndOfSequence,
+            11 => NalUnitType::EndOfStream,
+            12 => NalUnitType::Filler,
+            13 => NalUnitType::SpsExt,
+            14 => NalUnitType::PrefixNal,
+            15 => NalUnitType::SubsetSps,
+            16 => NalUnitType::DepthParameterSet,
+            19 => NalUnitType::NalUnitTypeCodedSliceAux,
+            20 => NalUnitType::CodedSliceExtension,
+            21 => NalUnitType::CodedSliceExtensionDepth,
+            _  => NalUnitType::Other(v)
         }
     }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            NalUnitType::Unspecified => 0,
+            NalUnitType::CodedSliceNonIdr => 1,
+            NalUnitType::CodedSliceDataPartitionA => 2,
+            NalUnitType::CodedSliceDataPartitionB => 3,
+            NalUnitType::CodedSliceDataPartitionC => 4,
+            NalUnitType::CodedSliceIdr => 5,
+            NalUnitType::SEI => 6,
+            NalUnitType::SPS => 7,
+            NalUnitType::PPS => 8,
+            NalUnitType::AUD => 9,
+            NalUnitType::EndOfSequence => 10,
+            NalUnitType::EndOfStream => 11,
+            NalUnitType::Filler => 12,
+            NalUnitType::SpsExt => 13,
+            NalUnitType::PrefixNal => 14,
+            NalUnitType::SubsetSps => 15,
+            NalUnitType::DepthParameterSet => 16,
+            NalUnitType::NalUnitTypeCodedSliceAux => 19,
+            NalUnitType::CodedSliceExtension => 20,
+            NalUnitType::CodedSliceExtensionDepth => 21,
+            NalUnitType::Other(v) => v,
+        }
+    }
+
+    // VCL NAL units of the primary coded picture, 7.4.1.2.2
+    pub fn is_vcl(self) -> bool { matches!(self.to_u8(), 1 ..= 5) }
 }
 
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct NAL {
     pub start: usize,
     pub unit_type: NalUnitType,
     pub end: usize,
-    pub data : Vec<u8>
+    pub forbidden_zero_bit: bool,
+    pub ref_idc: u8,
+    pub data : Bytes,   // shares the buffer the stream was read into
+}
+
+impl NAL {
+    // data is the NAL unit without start code, beginning with the NAL header byte
+    pub fn new(start: usize, end: usize, data: Bytes) -> Option<NAL> {
+        let b = *data.first()?;
+        Some(NAL{
+            start, end,
+            unit_type: NalUnitType::from_u8(get_nal_unit_type(b)),
+            forbidden_zero_bit: get_forbidden_zero_bit(b) == 1,
+            ref_idc: get_nal_ref_idc(b),
+            data,
+        })
+    }
 }
 
 fn get_forbidden_zero_bit(b: u8) -> u8 { (b & 0b10000000) >> 7 }
 fn get_nal_ref_idc(b: u8) -> u8        { (b & 0b01100000) >> 5 }
-fn get_nal_unit_type(b: u8) -> u8      { (b & 0b00011111) >> 0 }
+fn get_nal_unit_type(b: u8) -> u8      { b & 0b00011111 }
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> { // 7.3.2.1.1.1 Scaling list syntax
+    let mut last_scale = 8i32;
+    let mut next_scale = 8i32;
+    for _ in 0 .. size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 { last_scale = next_scale; }
+    }
+    Some(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sps { // 7.3.2.1.1 Sequence parameter set data syntax
+    pub profile_idc: u8,
+    pub constraint_flags: u8,   // constraint_set0_flag .. constraint_set5_flag + reserved_zero_2bits
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub bit_depth_luma_minus8: u32,
+    pub bit_depth_chroma_minus8: u32,
+    pub qpprime_y_zero_transform_bypass_flag: bool,
+    pub seq_scaling_matrix_present_flag: bool,
+    pub log2_max_frame_num_minus4: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb_minus4: u32,
+    pub delta_pic_order_always_zero_flag: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    pub offset_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u32,
+    pub gaps_in_frame_num_value_allowed_flag: bool,
+    pub pic_width_in_mbs_minus1: u32,
+    pub pic_height_in_map_units_minus1: u32,
+    pub frame_mbs_only_flag: bool,
+    pub mb_adaptive_frame_field_flag: bool,
+    pub direct_8x8_inference_flag: bool,
+    pub frame_cropping_flag: bool,
+    pub frame_crop_left_offset: u32,
+    pub frame_crop_right_offset: u32,
+    pub frame_crop_top_offset: u32,
+    pub frame_crop_bottom_offset: u32,
+    pub vui_parameters_present_flag: bool,
+    pub vui: Option<Vui>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HrdParameters { // E.1.2 HRD parameters syntax
+    pub cpb_cnt_minus1: u32,
+    pub bit_rate_scale: u8,
+    pub cpb_size_scale: u8,
+    pub initial_cpb_removal_delay_length_minus1: u8,
+    pub cpb_removal_delay_length_minus1: u8,
+    pub dpb_output_delay_length_minus1: u8,
+    pub time_offset_length: u8,
+}
+
+impl HrdParameters {
+    fn parse(r: &mut BitReader) -> Option<HrdParameters> {
+        let mut hrd = HrdParameters{cpb_cnt_minus1: r.read_ue()?, ..HrdParameters::default()};
+        if hrd.cpb_cnt_minus1 > 31 { return None; }
+        hrd.bit_rate_scale = r.read_bits(4)? as u8;
+        hrd.cpb_size_scale = r.read_bits(4)? as u8;
+        for _ in 0 ..= hrd.cpb_cnt_minus1 {
+            r.read_ue()?; // bit_rate_value_minus1
+            r.read_ue()?; // cpb_size_value_minus1
+            r.read_bit()?; // cbr_flag
+        }
+        hrd.initial_cpb_removal_delay_length_minus1 = r.read_bits(5)? as u8;
+        hrd.cpb_removal_delay_length_minus1 = r.read_bits(5)? as u8;
+        hrd.dpb_output_delay_length_minus1 = r.read_bits(5)? as u8;
+        hrd.time_offset_length = r.read_bits(5)? as u8;
+        Some(hrd)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Vui { // E.1.1 VUI parameters syntax
+    pub aspect_ratio_idc: u8,
+    pub sar_width: u16,
+    pub sar_height: u16,
+    pub overscan_appropriate_flag: bool,
+    pub video_format: u8,
+    pub video_full_range_flag: bool,
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub chroma_sample_loc_type_top_field: u32,
+    pub chroma_sample_loc_type_bottom_field: u32,
+    pub timing_info_present_flag: bool,
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate_flag: bool,
+    pub nal_hrd_parameters: Option<HrdParameters>,
+    pub vcl_hrd_parameters: Option<HrdParameters>,
+    pub low_delay_hrd_flag: bool,
+    pub pic_struct_present_flag: bool,
+    pub max_num_reorder_frames: Option<u32>,
+    pub max_dec_frame_buffering: Option<u32>,
+}
+
+impl Vui {
+    fn parse(r: &mut BitReader) -> Option<Vui> {
+        let mut vui = Vui{video_format: 5, colour_primaries: 2, transfer_characteristics: 2, matrix_coefficients: 2, ..Vui::default()};
+        let aspect_ratio_info_present_flag = r.read_bit()?;
+        if aspect_ratio_info_present_flag {
+            vui.aspect_ratio_idc = r.read_bits(8)? as u8;
+            if vui.aspect_ratio_idc == 255 { // Extended_SAR
+                vui.sar_width = r.read_bits(16)? as u16;
+                vui.sar_height = r.read_bits(16)? as u16;
+            }
+        }
+        let overscan_info_present_flag = r.read_bit()?;
+        if overscan_info_present_flag { vui.overscan_appropriate_flag = r.read_bit()?; }
+        let video_signal_type_present_flag = r.read_bit()?;
+        if video_signal_type_present_flag {
+            vui.video_format = r.read_bits(3)? as u8;
+            vui.video_full_range_flag = r.read_bit()?;
+            let colour_description_present_flag = r.read_bit()?;
+            if colour_description_present_flag {
+                vui.colour_primaries = r.read_bits(8)? as u8;
+                vui.transfer_characteristics = r.read_bits(8)? as u8;
+                vui.matrix_coefficients = r.read_bits(8)? as u8;
+            }
+        }
+        let chroma_loc_info_present_flag = r.read_bit()?;
+        if chroma_loc_info_present_flag {
+            vui.chroma_sample_loc_type_top_field = r.read_ue()?;
+            vui.chroma_sample_loc_type_bottom_field = r.read_ue()?;
+        }
+        vui.timing_info_present_flag = r.read_bit()?;
+        if vui.timing_info_present_flag {
+            vui.num_units_in_tick = r.read_bits(32)?;
+            vui.time_scale = r.read_bits(32)?;
+            vui.fixed_frame_rate_flag = r.read_bit()?;
+        }
+        let nal_hrd_parameters_present_flag = r.read_bit()?;
+        if nal_hrd_parameters_present_flag { vui.nal_hrd_parameters = Some(HrdParameters::parse(r)?); }
+        let vcl_hrd_parameters_present_flag = r.read_bit()?;
+        if vcl_hrd_parameters_present_flag { vui.vcl_hrd_parameters = Some(HrdParameters::parse(r)?); }
+        if nal_hrd_parameters_present_flag || vcl_hrd_parameters_present_flag {
+            vui.low_delay_hrd_flag = r.read_bit()?;
+        }
+        vui.pic_struct_present_flag = r.read_bit()?;
+        let bitstream_restriction_flag = r.read_bit()?;
+        if bitstream_restriction_flag {
+            r.read_bit()?; // motion_vectors_over_pic_boundaries_flag
+            r.read_ue()?; // max_bytes_per_pic_denom
+            r.read_ue()?; // max_bits_per_mb_denom
+            r.read_ue()?; // log2_max_mv_length_horizontal
+            r.read_ue()?; // log2_max_mv_length_vertical
+            vui.max_num_reorder_frames = Some(r.read_ue()?);
+            vui.max_dec_frame_buffering = Some(r.read_ue()?);
+        }
+        Some(vui)
+    }
+}
 
+// Media timescale and the duration of one frame in it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRate {
+    pub timescale: u32,
+    pub sample_duration: u32,
+}
 
-//fn get_nal_ref_idc(b: u8) -> u8 {
-////    let b = 0b10101010;
-////    println!("{:b}", b & 0b10000000);
-////    println!("{:b}", b & 0b10000000 << 1);
-////    println!("{:b}", (b & 0b10000000) >> 7);
-//    (b & 0b01110000) >> 4
-//}
+impl FrameRate {
+    // parses "25", "29.97" or "30000/1001"
+    pub fn parse(s: &str) -> Option<FrameRate> {
+        if let Some(pos) = s.find('/') {
+            let timescale = s[.. pos].trim().parse::<u32>().ok()?;
+            let sample_duration = s[pos + 1 ..].trim().parse::<u32>().ok()?;
+            if timescale == 0 || sample_duration == 0 { return None; }
+            return Some(FrameRate{timescale, sample_duration});
+        }
+        let fps = s.trim().parse::<f64>().ok()?;
+        if fps.is_nan() || fps <= 0.0 { return None; }
+        Some(FrameRate{timescale: (fps * 1000.0).round() as u32, sample_duration: 1000})
+    }
+}
+
+impl Sps {
+    // data is the NAL unit without start code, including the one byte NAL header
+    pub fn parse(data: &[u8]) -> Option<Sps> {
+        let mut sps = Sps::default();
+        let rbsp = ebsp_to_rbsp(data.get(1 ..)?);
+        let mut r = BitReader::new(&rbsp);
+        sps.profile_idc = r.read_bits(8)? as u8;
+        sps.constraint_flags = r.read_bits(8)? as u8;
+        sps.level_idc = r.read_bits(8)? as u8;
+        sps.seq_parameter_set_id = r.read_ue()?;
+
+        sps.chroma_format_idc = 1;
+        if sps.has_chroma_info() {
+            sps.chroma_format_idc = r.read_ue()?;
+            if sps.chroma_format_idc > 3 { return None; }
+            if sps.chroma_format_idc == 3 { sps.separate_colour_plane_flag = r.read_bit()?; }
+            sps.bit_depth_luma_minus8 = r.read_ue()?;
+            sps.bit_depth_chroma_minus8 = r.read_ue()?;
+            if sps.bit_depth_luma_minus8 > 6 || sps.bit_depth_chroma_minus8 > 6 { return None; }
+            sps.qpprime_y_zero_transform_bypass_flag = r.read_bit()?;
+            sps.seq_scaling_matrix_present_flag = r.read_bit()?;
+            if sps.seq_scaling_matrix_present_flag {
+                let count = if sps.chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0 .. count {
+                    let seq_scaling_list_present_flag = r.read_bit()?;
+                    if seq_scaling_list_present_flag {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
 
-fn find_nal(buf: &Vec<u8>, offset: usize) -> Option<NAL> {
-    let mut i = offset;
+        // 7.4.2.1.1 value ranges, the frame_num and POC computations shift by them
+        sps.log2_max_frame_num_minus4 = r.read_ue()?;
+        if sps.log2_max_frame_num_minus4 > 12 { return None; }
+        sps.pic_order_cnt_type = r.read_ue()?;
+        if sps.pic_order_cnt_type > 2 { return None; }
+        if sps.pic_order_cnt_type == 0 {
+            sps.log2_max_pic_order_cnt_lsb_minus4 = r.read_ue()?;
+            if sps.log2_max_pic_order_cnt_lsb_minus4 > 12 { return None; }
+        } else if sps.pic_order_cnt_type == 1 {
+            sps.delta_pic_order_always_zero_flag = r.read_bit()?;
+            sps.offset_for_non_ref_pic = r.read_se()?;
+            sps.offset_for_top_to_bottom_field = r.read_se()?;
+            let num_ref_frames_in_pic_order_cnt_cycle = r.read_ue()?;
+            if num_ref_frames_in_pic_order_cnt_cycle > 255 { return None; }
+            for _ in 0 .. num_ref_frames_in_pic_order_cnt_cycle {
+                sps.offset_for_ref_frame.push(r.read_se()?);
+            }
+        }
+        sps.max_num_ref_frames = r.read_ue()?;
+        sps.gaps_in_frame_num_value_allowed_flag = r.read_bit()?;
+        sps.pic_width_in_mbs_minus1 = r.read_ue()?;
+        sps.pic_height_in_map_units_minus1 = r.read_ue()?;
+        // the coded size in samples fits in 32 bits, for two fields per map unit too
+        if sps.pic_width_in_mbs_minus1 >= u32::MAX / 16 || sps.pic_height_in_map_units_minus1 >= u32::MAX / 32 { return None; }
+        sps.frame_mbs_only_flag = r.read_bit()?;
+        if !sps.frame_mbs_only_flag { sps.mb_adaptive_frame_field_flag = r.read_bit()?; }
+        sps.direct_8x8_inference_flag = r.read_bit()?;
+        sps.frame_cropping_flag = r.read_bit()?;
+        if sps.frame_cropping_flag {
+            sps.frame_crop_left_offset = r.read_ue()?;
+            sps.frame_crop_right_offset = r.read_ue()?;
+            sps.frame_crop_top_offset = r.read_ue()?;
+            sps.frame_crop_bottom_offset = r.read_ue()?;
+            // the cropping window keeps part of the picture
+            let (crop_unit_x, crop_unit_y) = sps.crop_units();
+            let crop = |a: u32, b: u32, unit: u32| a.checked_add(b).and_then(|sum| sum.checked_mul(unit));
+            if crop(sps.frame_crop_left_offset, sps.frame_crop_right_offset, crop_unit_x)? >= sps.coded_width()
+                || crop(sps.frame_crop_top_offset, sps.frame_crop_bottom_offset, crop_unit_y)? >= sps.coded_height() {
+                return None;
+            }
+        }
+        sps.vui_parameters_present_flag = r.read_bit()?;
+        if sps.vui_parameters_present_flag {
+            sps.vui = Vui::parse(&mut r);
+        }
 
-    loop { // start NAL     next_bits( 24 ) != 0x000001 && next_bits( 32 ) != 0x00000001
-        if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x01 { i+=3; break; }
-        if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x00 && buf[i+3] == 0x01 { i+=4; break; }
-        i += 1;
-        if i + 4 >= buf.len() { return None }
+        Some(sps)
     }
 
-    let mut nal = NAL{start: 0, unit_type: NalUnitType::Unspecified, end: 0, data: vec![]};
-    nal.start = i;
+    // profiles that carry chroma_format_idc, bit depths and scaling matrices in the SPS
+    pub fn has_chroma_info(&self) -> bool {
+        matches!(self.profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135)
+    }
 
-    loop { // start NAL     next_bits( 24 ) != 0x000000 && next_bits( 24 ) != 0x000001
-        if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x01 { break; }
-        if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x00 && buf[i+3] == 0x01 { break; }
-        i += 1;
-        if i + 4 >= buf.len() { return None }
+    // ChromaArrayType, 7.4.2.1.1
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane_flag { 0 } else { self.chroma_format_idc }
     }
-    nal.end = i;
 
-    Some(nal)
+    // SubWidthC, SubHeightC  Table 6-1
+    fn sub_width_height_c(&self) -> (u32, u32) {
+        match self.chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        }
+    }
+
+    pub fn coded_width(&self) -> u32 { (self.pic_width_in_mbs_minus1 + 1) * 16 }
+    pub fn coded_height(&self) -> u32 {
+        (2 - self.frame_mbs_only_flag as u32) * (self.pic_height_in_map_units_minus1 + 1) * 16
+    }
+
+    fn crop_units(&self) -> (u32, u32) { // CropUnitX, CropUnitY  (7-19) .. (7-22)
+        let frame_height_factor = 2 - self.frame_mbs_only_flag as u32;
+        if self.chroma_array_type() == 0 { return (1, frame_height_factor); }
+        let (sub_width_c, sub_height_c) = self.sub_width_height_c();
+        (sub_width_c, sub_height_c * frame_height_factor)
+    }
+
+    // frame rate from VUI timing_info, one frame lasts two clock ticks (E.2.1)
+    pub fn frame_rate(&self) -> Option<FrameRate> {
+        let vui = self.vui.as_ref()?;
+        if !vui.timing_info_present_flag || vui.num_units_in_tick == 0 || vui.time_scale == 0 { return None; }
+        Some(FrameRate{timescale: vui.time_scale, sample_duration: vui.num_units_in_tick.checked_mul(2)?})
+    }
+
+    // width of the decoded picture after frame cropping
+    pub fn width(&self) -> u32 {
+        let (crop_unit_x, _) = self.crop_units();
+        let crop = crop_unit_x * (self.frame_crop_left_offset + self.frame_crop_right_offset);
+        self.coded_width().saturating_sub(crop)
+    }
+
+    // height of the decoded frame after frame cropping
+    pub fn height(&self) -> u32 {
+        let (_, crop_unit_y) = self.crop_units();
+        let crop = crop_unit_y * (self.frame_crop_top_offset + self.frame_crop_bottom_offset);
+        self.coded_height().saturating_sub(crop)
+    }
 }
 
-pub fn nal_from_data(buf: &Vec<u8>) -> Option<NAL> {
-    let mut nal = NAL{start: 0, unit_type: NalUnitType::Unspecified, end: 0, data: vec![]};
-    let b = buf[0];
-    let nal_unit_type = get_nal_unit_type(b);
-    nal.unit_type = NalUnitType::from_u8(nal_unit_type).unwrap();
-    Some(nal)
+#[derive(Debug, Clone, Default)]
+pub struct Pps { // 7.3.2.2 Picture parameter set RBSP syntax
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    pub entropy_coding_mode_flag: bool,
+    pub bottom_field_pic_order_in_frame_present_flag: bool,
+    pub num_slice_groups_minus1: u32,
+    pub slice_group_map_type: u32,
+    pub num_ref_idx_l0_default_active_minus1: u32,
+    pub num_ref_idx_l1_default_active_minus1: u32,
+    pub weighted_pred_flag: bool,
+    pub weighted_bipred_idc: u8,
+    pub pic_init_qp_minus26: i32,
+    pub pic_init_qs_minus26: i32,
+    pub chroma_qp_index_offset: i32,
+    pub deblocking_filter_control_present_flag: bool,
+    pub constrained_intra_pred_flag: bool,
+    pub redundant_pic_cnt_present_flag: bool,
+    pub transform_8x8_mode_flag: bool,
+    pub pic_scaling_matrix_present_flag: bool,
+    pub second_chroma_qp_index_offset: i32,
 }
 
-pub fn get_nal(buf: &Vec<u8>, offset: usize) -> Option<NAL> {
-    let mut i = offset;
-    if i + 4 >= buf.len() { return None }
+impl Pps {
+    // data is the NAL unit without start code, including the one byte NAL header.
+    // The referenced SPS is only needed for the scaling lists of the transform_8x8 extension.
+    pub fn parse(data: &[u8], params: &ParameterSets) -> Option<Pps> {
+        let mut pps = Pps::default();
+        let rbsp = ebsp_to_rbsp(data.get(1 ..)?);
+        let mut r = BitReader::new(&rbsp);
+        pps.pic_parameter_set_id = r.read_ue()?;
+        pps.seq_parameter_set_id = r.read_ue()?;
+        pps.entropy_coding_mode_flag = r.read_bit()?;
+        pps.bottom_field_pic_order_in_frame_present_flag = r.read_bit()?;
+        pps.num_slice_groups_minus1 = r.read_ue()?;
+        if pps.num_slice_groups_minus1 > 0 {
+            pps.slice_group_map_type = r.read_ue()?;
+            match pps.slice_group_map_type {
+                0 => {
+                    for _ in 0 ..= pps.num_slice_groups_minus1 {
+                        r.read_ue()?; // run_length_minus1
+                    }
+                }
+                2 => {
+                    for _ in 0 .. pps.num_slice_groups_minus1 {
+                        r.read_ue()?; // top_left
+                        r.read_ue()?; // bottom_right
+                    }
+                }
+                3 ..= 5 => {
+                    r.read_bit()?; // slice_group_change_direction_flag
+                    r.read_ue()?; // slice_group_change_rate_minus1
+                }
+                6 => {
+                    let pic_size_in_map_units_minus1 = r.read_ue()?;
+                    // slice_group_id u(v), v = Ceil( Log2( num_slice_groups_minus1 + 1 ) )
+                    let bits = 32 - pps.num_slice_groups_minus1.leading_zeros() as usize;
+                    r.skip_bits(bits * (pic_size_in_map_units_minus1 as usize + 1))?;
+                }
+                _ => {}
+            }
+        }
+        pps.num_ref_idx_l0_default_active_minus1 = r.read_ue()?;
+        pps.num_ref_idx_l1_default_active_minus1 = r.read_ue()?;
+        pps.weighted_pred_flag = r.read_bit()?;
+        pps.weighted_bipred_idc = r.read_bits(2)? as u8;
+        pps.pic_init_qp_minus26 = r.read_se()?;
+        pps.pic_init_qs_minus26 = r.read_se()?;
+        pps.chroma_qp_index_offset = r.read_se()?;
+        pps.deblocking_filter_control_present_flag = r.read_bit()?;
+        pps.constrained_intra_pred_flag = r.read_bit()?;
+        pps.redundant_pic_cnt_present_flag = r.read_bit()?;
 
-    let mut start: usize = 0;
-    if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x01 { start = i + 3; }
-    if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x00 && buf[i+3] == 0x01 { start = i + 4; }
-    if start == 0 { println!("Can't find start"); return None; }
+        pps.second_chroma_qp_index_offset = pps.chroma_qp_index_offset;
+        if r.more_rbsp_data() {
+            pps.transform_8x8_mode_flag = r.read_bit()?;
+            pps.pic_scaling_matrix_present_flag = r.read_bit()?;
+            if pps.pic_scaling_matrix_present_flag {
+                let chroma_format_idc = params.sps.get(&pps.seq_parameter_set_id)
+                    .map(|sps| sps.parsed.chroma_format_idc).unwrap_or(1);
+                let count = 6 + if chroma_format_idc != 3 { 2 } else { 6 } * pps.transform_8x8_mode_flag as u32;
+                for i in 0 .. count {
+                    let pic_scaling_list_present_flag = r.read_bit()?;
+                    if pic_scaling_list_present_flag {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+            pps.second_chroma_qp_index_offset = r.read_se()?;
+        }
 
-    let mut nal = NAL{start: 0, unit_type: NalUnitType::Unspecified, end: 0, data: vec![]};
-    i = start;
-    nal.start = i;
+        Some(pps)
+    }
+}
 
-    let b = buf[start];
-    // let nal_header = buf[i];
-//    println!("b             {:b}    {:x}", b, b);
+#[derive(Debug, Clone)]
+pub struct ParameterSet<T> {
+    pub parsed: T,
+    pub nal: NAL,
+}
 
-//    let forbidden_zero_bit = get_forbidden_zero_bit(b);
-//    println!("forbidden_zero_bit {}", forbidden_zero_bit);
-//
-//    let nal_ref_idc = get_nal_ref_idc(b);
-//    println!("nal_ref_idc {}", nal_ref_idc);
-//
-    let nal_unit_type = get_nal_unit_type(b);
-//    println!("nal_unit_type {}", nal_unit_type);
+// All SPS, SPS extensions and PPS seen in the stream, keyed by their ids
+#[derive(Debug, Clone, Default)]
+pub struct ParameterSets {
+    pub sps: HashMap<u32, ParameterSet<Sps>>,
+    pub pps: HashMap<u32, ParameterSet<Pps>>,
+    pub sps_ext: HashMap<u32, NAL>,
+    pub last_sps_id: Option<u32>,
+}
 
-    nal.unit_type = NalUnitType::from_u8(nal_unit_type).unwrap();
+impl ParameterSets {
+    // stores the NAL if it is a SPS, SPS extension or PPS and returns the syntax of the SPS or PPS,
+    // None for any other NAL unit
+    pub fn insert(&mut self, nal: &NAL) -> Option<NalSyntax> {
+        match nal.unit_type {
+            NalUnitType::SPS => {
+                let sps = Sps::parse(&nal.data)?;
+                self.last_sps_id = Some(sps.seq_parameter_set_id);
+                let prev = self.sps.insert(sps.seq_parameter_set_id, ParameterSet{parsed: sps.clone(), nal: nal.clone()});
+                // the extension of a replaced SPS doesn't apply to the new one
+                if prev.is_some_and(|prev| prev.nal.data != nal.data) { self.sps_ext.remove(&sps.seq_parameter_set_id); }
+                Some(NalSyntax::Sps(sps))
+            }
+            NalUnitType::SpsExt => { // 7.3.2.1.2 starts with seq_parameter_set_id
+                let rbsp = ebsp_to_rbsp(nal.data.get(1 ..)?);
+                let seq_parameter_set_id = BitReader::new(&rbsp).read_ue()?;
+                self.sps_ext.insert(seq_parameter_set_id, nal.clone());
+                None
+            }
+            NalUnitType::PPS => {
+                let pps = Pps::parse(&nal.data, self)?;
+                self.pps.insert(pps.pic_parameter_set_id, ParameterSet{parsed: pps.clone(), nal: nal.clone()});
+                Some(NalSyntax::Pps(pps))
+            }
+            _ => None
+        }
+    }
 
+    // the PPS with the given id and the SPS it refers to
+    pub fn get_pair(&self, pic_parameter_set_id: u32) -> Option<(&ParameterSet<Sps>, &ParameterSet<Pps>)> {
+        let pps = self.pps.get(&pic_parameter_set_id)?;
+        let sps = self.sps.get(&pps.parsed.seq_parameter_set_id)?;
+        Some((sps, pps))
+    }
 
-    loop { // start NAL     next_bits( 24 ) != 0x000000 && next_bits( 24 ) != 0x000001
-        if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x01 { break; }
-        if buf[i] == 0x00 && buf[i+1] == 0x00 && buf[i+2] == 0x00 && buf[i+3] == 0x01 { break; }
-        i += 1;
-        if i + 4 >= buf.len() { return None }
+    // the most recently received SPS
+    pub fn latest_sps(&self) -> Option<&Sps> {
+        self.sps.get(&self.last_sps_id?).map(|sps| &sps.parsed)
     }
-    nal.end = i;
+}
 
-    nal.data = Vec::from(&buf[nal.start .. nal.end]);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SliceType { P = 0, B = 1, I = 2, SP = 3, SI = 4 } // Table 7-6
 
-    Some(nal)
+impl SliceType {
+    pub fn from_u32(v: u32) -> Option<SliceType> {
+        if v > 9 { return None; }
+        match v % 5 { // slice_type 5..9 also mean that all slices of the picture have the same type
+            0 => Some(SliceType::P),
+            1 => Some(SliceType::B),
+            2 => Some(SliceType::I),
+            3 => Some(SliceType::SP),
+            4 => Some(SliceType::SI),
+            _ => None
+        }
+    }
 }
 
-pub fn main_h264(path: &str) -> Result<(Vec<IDR>, NAL, NAL), ()> {
-    let mut file = std::fs::File::open(path).unwrap();
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents).unwrap();
-    let bytes = contents.clone();
+#[derive(Debug, Clone, Default)]
+pub struct SliceHeader { // 7.3.3 Slice header syntax, up to dec_ref_pic_marking()
+    pub nal_ref_idc: u8,
+    pub idr_pic_flag: bool,
+    pub first_mb_in_slice: u32,
+    pub slice_type: u32,
+    pub pic_parameter_set_id: u32,
+    pub colour_plane_id: u8,
+    pub frame_num: u32,
+    pub field_pic_flag: bool,
+    pub bottom_field_flag: bool,
+    pub idr_pic_id: u32,
+    pub pic_order_cnt_lsb: u32,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    pub redundant_pic_cnt: u32,
+    pub num_ref_idx_l0_active_minus1: u32,
+    pub num_ref_idx_l1_active_minus1: u32,
+    pub memory_management_control_operation_5: bool, // 7.4.3.3, resets frame_num and POC
+}
 
-    let mut buf = Cursor::new(contents.clone());
+fn skip_ref_pic_list_modification(r: &mut BitReader) -> Option<()> { // 7.3.3.1
+    let ref_pic_list_modification_flag = r.read_bit()?;
+    if ref_pic_list_modification_flag {
+        loop {
+            let modification_of_pic_nums_idc = r.read_ue()?;
+            match modification_of_pic_nums_idc {
+                0 | 1 => { r.read_ue()?; } // abs_diff_pic_num_minus1
+                2 => { r.read_ue()?; } // long_term_pic_num
+                3 => break,
+                _ => return None
+            }
+        }
+    }
+    Some(())
+}
 
-    let mut offset = 0;
-    let mut count = 0;
+fn skip_pred_weight_table(r: &mut BitReader, sh: &SliceHeader, sps: &Sps) -> Option<()> { // 7.3.3.2
+    r.read_ue()?; // luma_log2_weight_denom
+    if sps.chroma_array_type() != 0 { r.read_ue()?; } // chroma_log2_weight_denom
+    let mut lists = vec![sh.num_ref_idx_l0_active_minus1];
+    if sh.slice_type() == SliceType::B { lists.push(sh.num_ref_idx_l1_active_minus1); }
+    for num_ref_idx_active_minus1 in lists {
+        for _ in 0 ..= num_ref_idx_active_minus1 {
+            let luma_weight_flag = r.read_bit()?;
+            if luma_weight_flag { r.read_se()?; r.read_se()?; } // luma_weight, luma_offset
+            if sps.chroma_array_type() != 0 {
+                let chroma_weight_flag = r.read_bit()?;
+                if chroma_weight_flag {
+                    for _ in 0 .. 2 { r.read_se()?; r.read_se()?; } // chroma_weight, chroma_offset
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+const SLICE_HEADER_MAX_SIZE: usize = 2048;
+
+impl SliceHeader {
+    // data is the NAL unit without start code, including the one byte NAL header
+    pub fn parse(data: &[u8], params: &ParameterSets) -> Option<SliceHeader> {
+        let mut sh = SliceHeader::default();
+        let header = *data.first()?;
+        sh.nal_ref_idc = get_nal_ref_idc(header);
+        sh.idr_pic_flag = NalUnitType::from_u8(get_nal_unit_type(header)) == NalUnitType::CodedSliceIdr;
+
+        // only the beginning of the slice is converted, the slice header is much shorter
+        let rbsp = ebsp_to_rbsp(&data[1 .. data.len().min(SLICE_HEADER_MAX_SIZE)]);
+        let mut r = BitReader::new(&rbsp);
+        sh.first_mb_in_slice = r.read_ue()?;
+        sh.slice_type = r.read_ue()?;
+        SliceType::from_u32(sh.slice_type)?;
+        sh.pic_parameter_set_id = r.read_ue()?;
+        let (sps, pps) = params.get_pair(sh.pic_parameter_set_id)?;
+        let (sps, pps) = (&sps.parsed, &pps.parsed);
+
+        if sps.separate_colour_plane_flag { sh.colour_plane_id = r.read_bits(2)? as u8; }
+        sh.frame_num = r.read_bits(sps.log2_max_frame_num_minus4 as u8 + 4)?;
+        if !sps.frame_mbs_only_flag {
+            sh.field_pic_flag = r.read_bit()?;
+            if sh.field_pic_flag { sh.bottom_field_flag = r.read_bit()?; }
+        }
+        if sh.idr_pic_flag { sh.idr_pic_id = r.read_ue()?; }
+        if sps.pic_order_cnt_type == 0 {
+            sh.pic_order_cnt_lsb = r.read_bits(sps.log2_max_pic_order_cnt_lsb_minus4 as u8 + 4)?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !sh.field_pic_flag {
+                sh.delta_pic_order_cnt_bottom = r.read_se()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            sh.delta_pic_order_cnt[0] = r.read_se()?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !sh.field_pic_flag {
+                sh.delta_pic_order_cnt[1] = r.read_se()?;
+            }
+        }
+        if pps.redundant_pic_cnt_present_flag { sh.redundant_pic_cnt = r.read_ue()?; }
+
+        let slice_type = sh.slice_type();
+        if slice_type == SliceType::B { r.read_bit()?; } // direct_spatial_mv_pred_flag
+        sh.num_ref_idx_l0_active_minus1 = pps.num_ref_idx_l0_default_active_minus1;
+        sh.num_ref_idx_l1_active_minus1 = pps.num_ref_idx_l1_default_active_minus1;
+        if slice_type == SliceType::P || slice_type == SliceType::SP || slice_type == SliceType::B {
+            let num_ref_idx_active_override_flag = r.read_bit()?;
+            if num_ref_idx_active_override_flag {
+                sh.num_ref_idx_l0_active_minus1 = r.read_ue()?;
+                if slice_type == SliceType::B { sh.num_ref_idx_l1_active_minus1 = r.read_ue()?; }
+            }
+        }
+        if slice_type != SliceType::I && slice_type != SliceType::SI {
+            skip_ref_pic_list_modification(&mut r)?; // list 0
+            if slice_type == SliceType::B { skip_ref_pic_list_modification(&mut r)?; } // list 1
+        }
+        if (pps.weighted_pred_flag && (slice_type == SliceType::P || slice_type == SliceType::SP)) ||
+            (pps.weighted_bipred_idc == 1 && slice_type == SliceType::B) {
+            skip_pred_weight_table(&mut r, &sh, sps)?;
+        }
+        if sh.nal_ref_idc != 0 { // 7.3.3.3 Decoded reference picture marking syntax
+            if sh.idr_pic_flag {
+                r.read_bit()?; // no_output_of_prior_pics_flag
+                r.read_bit()?; // long_term_reference_flag
+            } else {
+                let adaptive_ref_pic_marking_mode_flag = r.read_bit()?;
+                if adaptive_ref_pic_marking_mode_flag {
+                    loop {
+                        let memory_management_control_operation = r.read_ue()?;
+                        match memory_management_control_operation {
+                            0 => break,
+                            1 => { r.read_ue()?; } // difference_of_pic_nums_minus1
+                            2 => { r.read_ue()?; } // long_term_pic_num
+                            3 => { r.read_ue()?; r.read_ue()?; } // difference_of_pic_nums_minus1, long_term_frame_idx
+                            4 => { r.read_ue()?; } // max_long_term_frame_idx_plus1
+                            5 => { sh.memory_management_control_operation_5 = true; }
+                            6 => { r.read_ue()?; } // long_term_frame_idx
+                            _ => return None
+                        }
+                    }
+                }
+            }
+        }
+
+        Some(sh)
+    }
+
+    pub fn slice_type(&self) -> SliceType { SliceType::from_u32(self.slice_type).unwrap() }
+
+    // 7.4.1.2.4 Detection of the first VCL NAL unit of a primary coded picture
+    pub fn is_first_vcl_of_new_picture(&self, prev: &SliceHeader) -> bool {
+        // pic_order_cnt_lsb, delta_pic_order_cnt_bottom and delta_pic_order_cnt[] are zero
+        // when absent, so comparing all of them covers both pic_order_cnt_type 0 and 1
+        self.frame_num != prev.frame_num ||
+        self.pic_parameter_set_id != prev.pic_parameter_set_id ||
+        self.field_pic_flag != prev.field_pic_flag ||
+        self.bottom_field_flag != prev.bottom_field_flag ||
+        (self.nal_ref_idc != prev.nal_ref_idc && (self.nal_ref_idc == 0 || prev.nal_ref_idc == 0)) ||
+        self.pic_order_cnt_lsb != prev.pic_order_cnt_lsb ||
+        self.delta_pic_order_cnt_bottom != prev.delta_pic_order_cnt_bottom ||
+        self.delta_pic_order_cnt != prev.delta_pic_order_cnt ||
+        self.idr_pic_flag != prev.idr_pic_flag ||
+        (self.idr_pic_flag && prev.idr_pic_flag && self.idr_pic_id != prev.idr_pic_id)
+    }
+}
+
+// 8.2.1 Decoding process for picture order count
+#[derive(Debug, Clone, Default)]
+pub struct PocState {
+    prev_pic_order_cnt_msb: i32,
+    prev_pic_order_cnt_lsb: i32,
+    prev_frame_num_offset: i32,
+    prev_frame_num: i32,
+    prev_has_mmco5: bool,
+    prev_ref_has_mmco5: bool,
+    prev_ref_top_field_order_cnt: i32,
+    prev_ref_is_bottom_field: bool,
+}
+
+impl PocState {
+    // returns PicOrderCnt() of the picture the slice belongs to
+    pub fn compute(&mut self, sh: &SliceHeader, sps: &Sps) -> i32 {
+        let max_frame_num = 1i32 << (sps.log2_max_frame_num_minus4 + 4);
+        let frame_num = sh.frame_num as i32;
+        let is_reference = sh.nal_ref_idc != 0;
+
+        let frame_num_offset = || { // (8-6), (8-11)
+            let prev_frame_num_offset = if self.prev_has_mmco5 { 0 } else { self.prev_frame_num_offset };
+            if sh.idr_pic_flag { 0 }
+            else if self.prev_frame_num > frame_num { prev_frame_num_offset + max_frame_num }
+            else { prev_frame_num_offset }
+        };
+
+        let (top, bottom, frame_num_offset) = match sps.pic_order_cnt_type {
+            0 => { // 8.2.1.1
+                let (prev_msb, prev_lsb) = if sh.idr_pic_flag {
+                    (0, 0)
+                } else if self.prev_ref_has_mmco5 {
+                    if self.prev_ref_is_bottom_field { (0, 0) } else { (0, self.prev_ref_top_field_order_cnt) }
+                } else {
+                    (self.prev_pic_order_cnt_msb, self.prev_pic_order_cnt_lsb)
+                };
+                let max_lsb = 1i32 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
+                let lsb = sh.pic_order_cnt_lsb as i32;
+                let msb = if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 { prev_msb + max_lsb }
+                    else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 { prev_msb - max_lsb }
+                    else { prev_msb };
+                if is_reference {
+                    self.prev_pic_order_cnt_msb = msb;
+                    self.prev_pic_order_cnt_lsb = lsb;
+                }
+                let top = msb + lsb;
+                let bottom = if sh.field_pic_flag { msb + lsb } else { top + sh.delta_pic_order_cnt_bottom };
+                (top, bottom, 0)
+            }
+            1 => { // 8.2.1.2
+                let frame_num_offset = frame_num_offset();
+                let cycle = sps.offset_for_ref_frame.len() as i32;
+                let mut abs_frame_num = if cycle != 0 { frame_num_offset + frame_num } else { 0 };
+                if !is_reference && abs_frame_num > 0 { abs_frame_num -= 1; }
+                let mut expected_pic_order_cnt = 0;
+                if abs_frame_num > 0 {
+                    let expected_delta_per_cycle: i32 = sps.offset_for_ref_frame.iter().sum();
+                    let pic_order_cnt_cycle_cnt = (abs_frame_num - 1) / cycle;
+                    let frame_num_in_cycle = (abs_frame_num - 1) % cycle;
+                    expected_pic_order_cnt = pic_order_cnt_cycle_cnt * expected_delta_per_cycle;
+                    expected_pic_order_cnt += sps.offset_for_ref_frame[..= frame_num_in_cycle as usize].iter().sum::<i32>();
+                }
+                if !is_reference { expected_pic_order_cnt += sps.offset_for_non_ref_pic; }
+                let top = expected_pic_order_cnt + sh.delta_pic_order_cnt[0];
+                let bottom = if !sh.field_pic_flag {
+                    top + sps.offset_for_top_to_bottom_field + sh.delta_pic_order_cnt[1]
+                } else {
+                    expected_pic_order_cnt + sps.offset_for_top_to_bottom_field + sh.delta_pic_order_cnt[0]
+                };
+                (top, bottom, frame_num_offset)
+            }
+            _ => { // 8.2.1.3
+                let frame_num_offset = frame_num_offset();
+                let temp_pic_order_cnt = if sh.idr_pic_flag { 0 }
+                    else if !is_reference { 2 * (frame_num_offset + frame_num) - 1 }
+                    else { 2 * (frame_num_offset + frame_num) };
+                (temp_pic_order_cnt, temp_pic_order_cnt, frame_num_offset)
+            }
+        };
+
+        let poc = if !sh.field_pic_flag { top.min(bottom) } else if sh.bottom_field_flag { bottom } else { top };
+
+        self.prev_frame_num = if sh.memory_management_control_operation_5 { 0 } else { frame_num };
+        self.prev_frame_num_offset = frame_num_offset;
+        self.prev_has_mmco5 = sh.memory_management_control_operation_5;
+        if is_reference {
+            self.prev_ref_has_mmco5 = sh.memory_management_control_operation_5;
+            self.prev_ref_is_bottom_field = sh.field_pic_flag && sh.bottom_field_flag;
+            // after memory_management_control_operation 5 the POC of the picture becomes relative to itself
+            self.prev_ref_top_field_order_cnt = if sh.field_pic_flag { 0 } else { top - top.min(bottom) };
+        }
+        poc
+    }
+}
+
+// Composition time offsets in frames for access units in decoding order: the display
+// position of every access unit (by PicOrderCnt) minus its decoding position
+pub fn composition_offsets(samples: &[AccessUnit]) -> Vec<i32> {
+    let pocs: Vec<i32> = samples.iter().map(|au| au.poc).collect();
+    poc_composition_offsets(&pocs)
+}
+
+// picture order counts in decoding order to composition offsets in frames
+pub fn poc_composition_offsets(pocs: &[i32]) -> Vec<i32> {
+    let mut display_order: Vec<usize> = (0 .. pocs.len()).collect();
+    display_order.sort_by_key(|i| pocs[*i]);
+    let mut offsets = vec![0i32; pocs.len()];
+    for (display_index, decode_index) in display_order.into_iter().enumerate() {
+        offsets[decode_index] = display_index as i32 - decode_index as i32;
+    }
+    offsets
+}
+
+// payloadType values, Annex D
+pub const SEI_BUFFERING_PERIOD: u32 = 0;
+pub const SEI_PIC_TIMING: u32 = 1;
+pub const SEI_USER_DATA_REGISTERED_ITU_T_T35: u32 = 4;
+pub const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+pub const SEI_RECOVERY_POINT: u32 = 6;
+
+#[derive(Debug, Clone, Default)]
+pub struct BufferingPeriod { // D.1.2
+    pub seq_parameter_set_id: u32,
+    pub nal_initial_cpb_removal_delay: Vec<(u32, u32)>, // initial_cpb_removal_delay, initial_cpb_removal_delay_offset
+    pub vcl_initial_cpb_removal_delay: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClockTimestamp { // D.1.3 clock_timestamp_flag == 1
+    pub nuit_field_based_flag: bool,
+    pub counting_type: u8,
+    pub discontinuity_flag: bool,
+    pub cnt_dropped_flag: bool,
+    pub n_frames: u8,
+    pub seconds: Option<u8>,
+    pub minutes: Option<u8>,
+    pub hours: Option<u8>,
+    pub time_offset: i32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PicTiming { // D.1.3
+    pub cpb_removal_delay: Option<u32>,
+    pub dpb_output_delay: Option<u32>,
+    pub pic_struct: Option<u8>,
+    pub clock_timestamps: Vec<Option<ClockTimestamp>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UserDataRegistered { // D.1.6
+    pub itu_t_t35_country_code: u8,
+    pub itu_t_t35_country_code_extension_byte: Option<u8>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UserDataUnregistered { // D.1.7
+    pub uuid_iso_iec_11578: [u8; 16],
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryPoint { // D.1.8
+    pub recovery_frame_cnt: u32,
+    pub exact_match_flag: bool,
+    pub broken_link_flag: bool,
+    pub changing_slice_group_idc: u8,
+}
+
+#[derive(Debug, Clone)]
+pub enum SeiPayload {
+    BufferingPeriod(BufferingPeriod),
+    PicTiming(PicTiming),
+    UserDataRegistered(UserDataRegistered),
+    UserDataUnregistered(UserDataUnregistered),
+    RecoveryPoint(RecoveryPoint),
+    Other(Vec<u8>), // not decoded or not decodable without the SPS, the payload RBSP bytes
+}
+
+#[derive(Debug, Clone)]
+pub struct SeiMessage { // 7.3.2.3.1 Supplemental enhancement information message syntax
+    pub payload_type: u32,
+    pub payload_size: u32,
+    pub payload: SeiPayload,
+}
+
+impl BufferingPeriod {
+    fn parse(data: &[u8], params: &ParameterSets) -> Option<BufferingPeriod> {
+        let mut r = BitReader::new(data);
+        let mut bp = BufferingPeriod{seq_parameter_set_id: r.read_ue()?, ..BufferingPeriod::default()};
+        let vui = params.sps.get(&bp.seq_parameter_set_id)?.parsed.vui.as_ref()?;
+        if let Some(hrd) = &vui.nal_hrd_parameters { // NalHrdBpPresentFlag
+            let bits = hrd.initial_cpb_removal_delay_length_minus1 + 1;
+            for _ in 0 ..= hrd.cpb_cnt_minus1 {
+                bp.nal_initial_cpb_removal_delay.push((r.read_bits(bits)?, r.read_bits(bits)?));
+            }
+        }
+        if let Some(hrd) = &vui.vcl_hrd_parameters { // VclHrdBpPresentFlag
+            let bits = hrd.initial_cpb_removal_delay_length_minus1 + 1;
+            for _ in 0 ..= hrd.cpb_cnt_minus1 {
+                bp.vcl_initial_cpb_removal_delay.push((r.read_bits(bits)?, r.read_bits(bits)?));
+            }
+        }
+        Some(bp)
+    }
+}
+
+impl PicTiming {
+    fn parse(data: &[u8], sps: &Sps) -> Option<PicTiming> {
+        let mut r = BitReader::new(data);
+        let mut pt = PicTiming::default();
+        let vui = sps.vui.as_ref()?;
+        // CpbDpbDelaysPresentFlag, the lengths are the same in the NAL and VCL HRD parameters
+        if let Some(hrd) = vui.nal_hrd_parameters.as_ref().or(vui.vcl_hrd_parameters.as_ref()) {
+            pt.cpb_removal_delay = Some(r.read_bits(hrd.cpb_removal_delay_length_minus1 + 1)?);
+            pt.dpb_output_delay = Some(r.read_bits(hrd.dpb_output_delay_length_minus1 + 1)?);
+        }
+        if vui.pic_struct_present_flag {
+            let pic_struct = r.read_bits(4)? as u8;
+            pt.pic_struct = Some(pic_struct);
+            let num_clock_ts = match pic_struct { // Table D-1
+                0 ..= 2 => 1,
+                3 | 4 | 7 => 2,
+                5 | 6 | 8 => 3,
+                _ => 0
+            };
+            let time_offset_length = vui.nal_hrd_parameters.as_ref().or(vui.vcl_hrd_parameters.as_ref())
+                .map(|hrd| hrd.time_offset_length).unwrap_or(24);
+            for _ in 0 .. num_clock_ts {
+                let clock_timestamp_flag = r.read_bit()?;
+                if !clock_timestamp_flag { pt.clock_timestamps.push(None); continue; }
+                let mut ct = ClockTimestamp::default();
+                r.skip_bits(2)?; // ct_type
+                ct.nuit_field_based_flag = r.read_bit()?;
+                ct.counting_type = r.read_bits(5)? as u8;
+                let full_timestamp_flag = r.read_bit()?;
+                ct.discontinuity_flag = r.read_bit()?;
+                ct.cnt_dropped_flag = r.read_bit()?;
+                ct.n_frames = r.read_bits(8)? as u8;
+                if full_timestamp_flag {
+                    ct.seconds = Some(r.read_bits(6)? as u8);
+                    ct.minutes = Some(r.read_bits(6)? as u8);
+                    ct.hours = Some(r.read_bits(5)? as u8);
+                } else if r.read_bit()? { // seconds_flag
+                    ct.seconds = Some(r.read_bits(6)? as u8);
+                    if r.read_bit()? { // minutes_flag
+                        ct.minutes = Some(r.read_bits(6)? as u8);
+                        if r.read_bit()? { // hours_flag
+                            ct.hours = Some(r.read_bits(5)? as u8);
+                        }
+                    }
+                }
+                if time_offset_length > 0 { // i(v)
+                    let v = r.read_bits(time_offset_length)?;
+                    ct.time_offset = ((v << (32 - time_offset_length as u32)) as i32) >> (32 - time_offset_length as u32);
+                }
+                pt.clock_timestamps.push(Some(ct));
+            }
+        }
+        Some(pt)
+    }
+}
+
+impl RecoveryPoint {
+    fn parse(data: &[u8]) -> Option<RecoveryPoint> {
+        let mut r = BitReader::new(data);
+        Some(RecoveryPoint{
+            recovery_frame_cnt: r.read_ue()?,
+            exact_match_flag: r.read_bit()?,
+            broken_link_flag: r.read_bit()?,
+            changing_slice_group_idc: r.read_bits(2)? as u8,
+        })
+    }
+}
+
+fn parse_sei_payload(payload_type: u32, data: &[u8], params: &ParameterSets) -> Option<SeiPayload> {
+    match payload_type {
+        SEI_BUFFERING_PERIOD => Some(SeiPayload::BufferingPeriod(BufferingPeriod::parse(data, params)?)),
+        SEI_PIC_TIMING => Some(SeiPayload::PicTiming(PicTiming::parse(data, params.latest_sps()?)?)),
+        SEI_USER_DATA_REGISTERED_ITU_T_T35 => {
+            let itu_t_t35_country_code = *data.first()?;
+            let mut ud = UserDataRegistered{itu_t_t35_country_code, ..UserDataRegistered::default()};
+            let mut i = 1;
+            if itu_t_t35_country_code == 0xFF {
+                ud.itu_t_t35_country_code_extension_byte = Some(*data.get(1)?);
+                i = 2;
+            }
+            ud.payload = data[i ..].to_vec();
+            Some(SeiPayload::UserDataRegistered(ud))
+        }
+        SEI_USER_DATA_UNREGISTERED => {
+            if data.len() < 16 { return None; }
+            let mut ud = UserDataUnregistered{payload: data[16 ..].to_vec(), ..UserDataUnregistered::default()};
+            ud.uuid_iso_iec_11578.copy_from_slice(&data[.. 16]);
+            Some(SeiPayload::UserDataUnregistered(ud))
+        }
+        SEI_RECOVERY_POINT => Some(SeiPayload::RecoveryPoint(RecoveryPoint::parse(data)?)),
+        _ => None
+    }
+}
+
+// all sei_message() of a SEI NAL unit, data includes the NAL header byte  7.3.2.3
+pub fn parse_sei(data: &[u8], params: &ParameterSets) -> Vec<SeiMessage> {
+    let mut messages = vec![];
+    let rbsp = ebsp_to_rbsp(data.get(1 ..).unwrap_or(&[]));
+    let mut i = 0;
+    while i < rbsp.len() && rbsp[i] != 0x80 { // 0x80 is rbsp_trailing_bits()
+        let mut payload_type = 0u32;
+        while i < rbsp.len() && rbsp[i] == 0xFF { payload_type += 255; i += 1; }
+        if i >= rbsp.len() { break; }
+        payload_type += rbsp[i] as u32; i += 1;
+        let mut payload_size = 0usize;
+        while i < rbsp.len() && rbsp[i] == 0xFF { payload_size += 255; i += 1; }
+        if i >= rbsp.len() { break; }
+        payload_size += rbsp[i] as usize; i += 1;
+        if i + payload_size > rbsp.len() { break; }
+
+        let data = &rbsp[i .. i + payload_size];
+        let payload = parse_sei_payload(payload_type, data, params).unwrap_or_else(|| SeiPayload::Other(data.to_vec()));
+        messages.push(SeiMessage{payload_type, payload_size: payload_size as u32, payload});
+        i += payload_size;
+    }
+    messages
+}
+
+// Groups NAL units in decoding order into access units
+#[derive(Default)]
+pub struct AccessUnitBuilder {
+    current: AccessUnit,
+    has_vcl: bool,
+    last_slice: Option<SliceHeader>,
+    poc: PocState,
+}
+
+impl AccessUnitBuilder {
+    // stores the parameter sets in params, returns the previous access unit when the NAL starts a new one
+    pub fn push(&mut self, nal: NAL, params: &mut ParameterSets) -> Option<AccessUnit> {
+        let mut syntax = params.insert(&nal).unwrap_or(NalSyntax::Other);
+        let params = &*params;
+        let mut new_access_unit = false;
+        let mut slice = None;
+        let mut damaged = false;
+        match nal.unit_type {
+            // 7.4.1.2.3: these NAL units after the last VCL NAL unit of a primary coded picture
+            // specify the start of a new access unit
+            NalUnitType::AUD | NalUnitType::SPS | NalUnitType::PPS | NalUnitType::SEI |
+            NalUnitType::PrefixNal | NalUnitType::SubsetSps | NalUnitType::DepthParameterSet |
+            NalUnitType::Other(17) | NalUnitType::Other(18) => {
+                new_access_unit = self.has_vcl;
+            }
+            NalUnitType::CodedSliceNonIdr | NalUnitType::CodedSliceIdr | NalUnitType::CodedSliceDataPartitionA => {
+                slice = SliceHeader::parse(&nal.data, params);
+                match &slice {
+                    Some(slice) => {
+                        if let Some(prev) = &self.last_slice {
+                            new_access_unit = self.has_vcl && slice.redundant_pic_cnt == 0 && slice.is_first_vcl_of_new_picture(prev);
+                        }
+                        if slice.redundant_pic_cnt == 0 { self.last_slice = Some(slice.clone()); }
+                    }
+                    None => {
+                        // nothing tells whether the slice belongs to the picture before it, it starts a damaged one
+                        eprintln!("Can't parse slice header at {}", nal.start);
+                        new_access_unit = self.has_vcl;
+                        damaged = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let mut complete = None;
+        if new_access_unit { complete = self.flush(); }
+        if nal.unit_type.is_vcl() { self.has_vcl = true; }
+        self.current.is_damaged |= damaged;
+        if nal.unit_type == NalUnitType::SEI {
+            syntax = NalSyntax::Sei(parse_sei(&nal.data, params));
+        }
+        if let Some(slice) = slice {
+            if slice.redundant_pic_cnt == 0 {
+                self.current.is_idr |= slice.idr_pic_flag;
+                self.current.is_reference |= slice.nal_ref_idc != 0;
+                if self.current.slice.is_none() {
+                    if let Some((sps, _)) = params.get_pair(slice.pic_parameter_set_id) {
+                        self.current.poc = self.poc.compute(&slice, &sps.parsed);
+                    }
+                    self.current.slice = Some(slice.clone());
+                }
+            }
+            syntax = NalSyntax::Slice(slice, self.current.poc);
+        }
+        self.current.nals.push(nal);
+        self.current.syntax.push(syntax);
+        complete
+    }
+
+    // returns the last, not yet completed access unit
+    pub fn flush(&mut self) -> Option<AccessUnit> {
+        self.has_vcl = false;
+        if self.current.nals.is_empty() { return None; }
+        Some(std::mem::take(&mut self.current))
+    }
+}
+
+// Why the resilient mode dropped a part of the stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Damage {
+    ForbiddenZeroBit,   // NAL unit with forbidden_zero_bit set, garbage or a bit error
+    DamagedPicture,     // access unit with a dropped NAL unit, a broken slice header or without its first slice
+    FrameNumGap,        // frame_num skips a reference picture
+    WaitingForIdr,      // decodable, but predicted from a damaged picture
+}
+
+// One entry of the corruption report
+#[derive(Debug, Clone)]
+pub struct Corruption {
+    pub offset: usize,  // stream position of the first dropped NAL unit
+    pub size: usize,    // bytes of the dropped NAL units
+    pub damage: Damage,
+}
+
+impl Corruption {
+    fn of_access_unit(au: &AccessUnit, damage: Damage) -> Corruption {
+        let offset = au.nals.first().map_or(0, |nal| nal.start);
+        Corruption{offset, size: au.nals.iter().map(|nal| nal.end - nal.start).sum(), damage}
+    }
+}
+
+// Iterator over the access units of a NAL unit stream, keeps track of the parameter sets.
+// Complementary field pairs of PAFF and MBAFF streams are returned as one access unit, a sample holds a frame.
+pub struct AccessUnits<I: Iterator<Item=NAL>> {
+    nals: I,
+    builder: AccessUnitBuilder,
+    params: ParameterSets,
+    pending: Option<AccessUnit>, // read ahead while looking for the second field of a pair
+    resilient: bool,             // drops NAL units with forbidden_zero_bit set
+    dropped: bool,               // a NAL unit was dropped after the last VCL NAL unit
+    corruptions: Vec<Corruption>,
+    count: usize,                // access units returned so far
+}
+
+impl<I: Iterator<Item=NAL>> AccessUnits<I> {
+    pub fn new(nals: I) -> AccessUnits<I> {
+        AccessUnits{
+            nals, builder: AccessUnitBuilder::default(), params: ParameterSets::default(), pending: None,
+            resilient: false, dropped: false, corruptions: vec![], count: 0,
+        }
+    }
+
+    // parameter sets received so far
+    pub fn params(&self) -> &ParameterSets { &self.params }
+
+    pub fn nals_mut(&mut self) -> &mut I { &mut self.nals }
+
+    // the next coded picture: a frame or a single field
+    fn next_picture(&mut self) -> Option<AccessUnit> {
+        for nal in &mut self.nals {
+            if self.resilient && nal.forbidden_zero_bit {
+                // the Annex B parser has already resynced at the following start code
+                self.corruptions.push(Corruption{offset: nal.start, size: nal.end - nal.start, damage: Damage::ForbiddenZeroBit});
+                self.dropped = true;
+                continue;
+            }
+            let is_vcl = nal.unit_type.is_vcl();
+            let had_vcl = self.builder.has_vcl;
+            let complete = self.builder.push(nal, &mut self.params);
+            if self.dropped && is_vcl {
+                // a hole between two slices of one picture. A lost first slice or a lost picture are found
+                // later by the first_mb_in_slice and frame_num checks, garbage between pictures costs nothing.
+                if complete.is_none() && had_vcl { self.builder.current.is_damaged = true; }
+                self.dropped = false;
+            }
+            if complete.is_some() { return complete; }
+        }
+        self.builder.flush()
+    }
+}
+
+impl<I: Iterator<Item=NAL>> Iterator for AccessUnits<I> {
+    type Item = AccessUnit;
+
+    fn next(&mut self) -> Option<AccessUnit> {
+        let mut au = self.pending.take().or_else(|| self.next_picture())?;
+        if au.is_field() {
+            match self.next_picture() {
+                Some(second) if second.is_second_field_of(&au) => au.merge_field(second),
+                next => self.pending = next, // unpaired field, stored as a sample of its own
+            }
+        }
+        au.index = self.count;
+        self.count += 1;
+        Some(au)
+    }
+}
+
+// Splits access units into groups that start at an IDR picture or a recovery point,
+// independently of how often the encoder repeats the parameter sets
+#[derive(Default)]
+pub struct GopBuilder {
+    current: Vec<AccessUnit>,
+}
+
+impl GopBuilder {
+    // returns the previous group when the access unit starts a new one
+    pub fn push(&mut self, au: AccessUnit) -> Option<IDR> {
+        let mut complete = None;
+        if au.is_random_access_point() { complete = self.finish(); }
+        self.current.push(au);
+        complete
+    }
+
+    // returns the last, not yet completed group
+    pub fn finish(&mut self) -> Option<IDR> {
+        if self.current.is_empty() { return None; }
+        Some(IDR{samples: std::mem::take(&mut self.current), sps: None, pps: None, sps_ext: None})
+    }
+}
+
+// Incremental parser of an Annex B byte stream (B.1). Accepts the stream in chunks of any size
+// and returns every NAL unit as soon as the start code of the following one has been received.
+#[derive(Default)]
+pub struct AnnexBParser {
+    buf: BytesMut,
+    offset: usize,              // position of buf[0] in the stream
+    nal_start: Option<usize>,   // index in buf of the first byte of the current NAL unit
+    scan: usize,                // index in buf to continue the start code search from
+}
+
+impl AnnexBParser {
+    pub fn new() -> AnnexBParser { AnnexBParser::default() }
+
+    // the returned NAL units are slices of the parser buffer, it is reallocated for the following data
+    // and freed when the last of them is dropped
+    pub fn push(&mut self, data: &[u8]) -> Vec<NAL> {
+        self.buf.extend_from_slice(data);
+        let mut ranges = vec![];
+        let mut i = self.scan;
+        while i + 3 <= self.buf.len() {
+            if self.buf[i + 2] > 1 { i += 3; continue; } // no start code can begin at i, i+1 or i+2
+            if self.buf[i] == 0x00 && self.buf[i+1] == 0x00 && self.buf[i+2] == 0x01 { // start_code_prefix_one_3bytes
+                if let Some(start) = self.nal_start {
+                    ranges.extend(self.nal_range(start, i));
+                }
+                self.nal_start = Some(i + 3);
+                i += 3;
+            } else {
+                i += 1;
+            }
+        }
+        // the last two bytes can be the beginning of a start code split between chunks
+        self.scan = self.buf.len().saturating_sub(2).max(self.nal_start.unwrap_or(0));
+
+        let consumed = self.nal_start.unwrap_or(self.scan);
+        let nals = self.split_nals(consumed, ranges);
+        self.scan -= consumed;
+        self.nal_start = self.nal_start.map(|start| start - consumed);
+        nals
+    }
+
+    // the end of the stream also ends the last NAL unit
+    pub fn finish(&mut self) -> Option<NAL> {
+        let range = self.nal_start.and_then(|start| self.nal_range(start, self.buf.len()));
+        let consumed = self.buf.len();
+        self.nal_start = None;
+        self.scan = 0;
+        self.split_nals(consumed, range.into_iter().collect()).pop()
+    }
+
+    fn nal_range(&self, start: usize, mut end: usize) -> Option<(usize, usize)> {
+        // trailing_zero_8bits and the leading zero_byte of a 4 byte start code do not belong to the NAL unit
+        while end > start && self.buf[end - 1] == 0x00 { end -= 1; }
+        if end == start { return None; }
+        Some((start, end))
+    }
+
+    // moves the first consumed bytes out of the buffer, the ranges are NAL units within them
+    fn split_nals(&mut self, consumed: usize, ranges: Vec<(usize, usize)>) -> Vec<NAL> {
+        let data = self.buf.split_to(consumed).freeze();
+        let offset = self.offset;
+        self.offset += consumed;
+        ranges.into_iter().filter_map(|(start, end)| NAL::new(offset + start, offset + end, data.slice(start, end))).collect()
+    }
+}
+
+// Parser of NAL units prefixed with their big endian length, as in the samples of an
+// ISO/IEC 14496-15 track (AVCC). The length field is 1, 2 or 4 bytes long.
+pub struct AvccParser {
+    length_size: u8,
+    buf: BytesMut,
+    offset: usize,               // position of buf[0] in the stream
+    skipped_from: Option<usize>, // position of a corrupt length field while looking for the next NAL unit
+}
+
+// longer NAL units come from a corrupt length field, a 4 byte one would buffer the rest of the stream
+const MAX_NAL_SIZE: usize = 64 * 1024 * 1024;
+// longest NAL unit to resync on after a corrupt length field, the resync waits for its end
+const MAX_RESYNC_NAL_SIZE: usize = 1024 * 1024;
+
+impl AvccParser {
+    // the length size is 1, 2 or 4, see MuxOptions for the check
+    pub fn new(length_size: u8) -> AvccParser {
+        AvccParser{length_size, buf: BytesMut::new(), offset: 0, skipped_from: None}
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Vec<NAL> {
+        self.buf.extend_from_slice(data);
+        self.parse(false)
+    }
+
+    fn parse(&mut self, at_end: bool) -> Vec<NAL> {
+        let mut ranges = vec![];
+        let length_size = self.length_size as usize;
+        let mut pos = 0;
+        while self.buf.len() - pos >= length_size {
+            let length = read_nal_length(&self.buf[pos ..], self.length_size);
+            if self.skipped_from.is_none() && length > MAX_NAL_SIZE {
+                eprintln!("Corrupt NAL unit length {} at {}", length, self.offset + pos);
+                self.skipped_from = Some(self.offset + pos);
+            }
+            if self.skipped_from.is_some() {
+                match self.resyncs_at(pos, at_end) {
+                    Some(true) => self.report_skipped(self.offset + pos),
+                    Some(false) => { pos += 1; continue; }
+                    None => break,
+                }
+            }
+            let start = pos + length_size;
+            if self.buf.len() - start < length { break; }
+            ranges.push((start, start + length));
+            pos = start + length;
+        }
+        let data = self.buf.split_to(pos).freeze();
+        let offset = self.offset;
+        self.offset += pos;
+        ranges.into_iter().filter_map(|(start, end)| NAL::new(offset + start, offset + end, data.slice(start, end))).collect()
+    }
+
+    // true when the NAL unit at pos and the next one have a plausible length and a valid header,
+    // None while they are not in the buffer yet
+    fn resyncs_at(&self, pos: usize, at_end: bool) -> Option<bool> {
+        let length_size = self.length_size as usize;
+        let valid_at = |pos: usize, max_size: usize| {
+            if self.buf.len() == pos && at_end { return Some((true, pos)); }
+            if self.buf.len() <= pos + length_size { return if at_end { Some((false, pos)) } else { None }; }
+            let length = read_nal_length(&self.buf[pos ..], self.length_size);
+            let valid = length != 0 && length <= max_size && get_forbidden_zero_bit(self.buf[pos + length_size]) == 0;
+            Some((valid, pos + length_size + length))
+        };
+        match valid_at(pos, MAX_RESYNC_NAL_SIZE)? {
+            (true, next) => valid_at(next, MAX_NAL_SIZE).map(|(valid, _)| valid),
+            _ => Some(false),
+        }
+    }
+
+    fn report_skipped(&mut self, end: usize) {
+        if let Some(start) = self.skipped_from.take() {
+            eprintln!("Skipped {} bytes at {} to the next NAL unit", end - start, start);
+        }
+    }
+
+    // resyncs on what is left after a corrupt length field, a NAL unit still waiting for its bytes is truncated
+    pub fn finish(&mut self) -> Vec<NAL> {
+        let nals = self.parse(true);
+        let end = self.offset + self.buf.len();
+        if self.skipped_from.is_some() {
+            self.report_skipped(end);
+        } else if !self.buf.is_empty() {
+            eprintln!("Truncated NAL unit at {}: {} bytes left", self.offset, self.buf.len());
+        }
+        self.offset = end;
+        self.buf.clear();
+        nals
+    }
+}
+
+fn read_nal_length(data: &[u8], length_size: u8) -> usize {
+    data[.. length_size as usize].iter().fold(0usize, |length, b| (length << 8) | *b as usize)
+}
 
-    let mut idrs = vec![];
-    let mut idr = IDR{samples: vec![]};
-    let mut first = true;
-    let mut sps = NAL{start: 0, end: 0, data: vec![], unit_type: NalUnitType::SPS,};
-    let mut pps = NAL{start: 0, end: 0, data: vec![], unit_type: NalUnitType::PPS};
-    loop {
-        let nal = get_nal(&contents, offset);
-        if nal.is_none() { break }
-        let nal = nal.unwrap();
-        if first {
-            if nal.unit_type == NalUnitType::SPS { sps = nal.clone(); }
-            if nal.unit_type == NalUnitType::SPS { pps = nal.clone(); }
-            first = false;
-        }
-        if nal.unit_type == NalUnitType::SPS {
-            if idr.samples.len() > 0 {
-                idrs.push(idr);
-                idr = IDR{samples: vec![]};
-            }
-        }
-        idr.samples.push((nal.unit_type.clone(), nal.data.clone()));
-        println!("{}:     {:?}   size: {}", count, nal.unit_type, nal.end - nal.start);
-        offset = nal.end;
+pub trait NalParser {
+    fn push(&mut self, data: &[u8]) -> Vec<NAL>;
+    fn finish(&mut self) -> Vec<NAL>;
+}
+
+impl NalParser for AnnexBParser {
+    fn push(&mut self, data: &[u8]) -> Vec<NAL> { AnnexBParser::push(self, data) }
+    fn finish(&mut self) -> Vec<NAL> { AnnexBParser::finish(self).into_iter().collect() }
+}
+
+impl NalParser for AvccParser {
+    fn push(&mut self, data: &[u8]) -> Vec<NAL> { AvccParser::push(self, data) }
+    fn finish(&mut self) -> Vec<NAL> { AvccParser::finish(self) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    AnnexB,     // NAL units separated by start codes
+    Avcc(u8),   // NAL units prefixed with a 1, 2 or 4 byte length
+}
+
+impl StreamFormat {
+    // guesses the format from the first bytes of the stream
+    pub fn detect(data: &[u8]) -> StreamFormat {
+        let zeros = data.iter().take_while(|b| **b == 0x00).count();
+        let start_code = zeros >= 2 && data.get(zeros) == Some(&0x01);
+        // a 4 byte length of 256..511 also starts with 0x00 0x00 0x01
+        if start_code && !avcc_plausible(data, 4) { return StreamFormat::AnnexB; }
+        for length_size in [4, 2, 1] {
+            if avcc_plausible(data, length_size) { return StreamFormat::Avcc(length_size); }
+        }
+        StreamFormat::AnnexB
+    }
+
+    pub fn parser(&self) -> Box<dyn NalParser> {
+        match self {
+            StreamFormat::AnnexB => Box::new(AnnexBParser::new()),
+            StreamFormat::Avcc(length_size) => Box::new(AvccParser::new(*length_size)),
+        }
+    }
+}
+
+// true when the data reads as a sequence of length prefixed NAL units with valid headers,
+// at least two of them or one that ends exactly at the end of the data
+fn avcc_plausible(data: &[u8], length_size: u8) -> bool {
+    let length_size = length_size as usize;
+    let mut pos = 0;
+    let mut count = 0;
+    while data.len() - pos >= length_size && count < 8 {
+        let length = read_nal_length(&data[pos ..], length_size as u8);
+        let start = pos + length_size;
+        if length == 0 || data.len() - start < length { break; }
+        // only the forbidden_zero_bit is common to the H.264 and H.265 NAL unit headers
+        if get_forbidden_zero_bit(data[start]) != 0 { return false; }
+        pos = start + length;
         count += 1;
     }
+    count >= 2 || (count == 1 && pos == data.len())
+}
+
+const PROBE_SIZE: usize = 64 * 1024;
+
+// NAL units read from a file, pipe or socket in Annex B or AVCC format
+pub struct NalReader<R: Read> {
+    reader: R,
+    format: Option<StreamFormat>,
+    parser: Option<Box<dyn NalParser>>,
+    pending: std::collections::VecDeque<NAL>,
+    chunk: Vec<u8>, // read buffer, the parsers copy what they keep
+    done: bool,
+    error: Option<std::io::Error>, // the read error that ended the stream
+}
+
+impl<R: Read> NalReader<R> {
+    // the format is detected from the first bytes when None
+    pub fn new(reader: R, format: Option<StreamFormat>) -> NalReader<R> {
+        NalReader{reader, format, parser: None, pending: Default::default(), chunk: vec![0u8; PROBE_SIZE], done: false, error: None}
+    }
+
+    // a read error ends the NAL units like the end of the stream, the caller has to check for it
+    pub fn take_error(&mut self) -> Option<std::io::Error> { self.error.take() }
+
+    fn read(reader: &mut R, chunk: &mut [u8], error: &mut Option<std::io::Error>) -> usize {
+        loop {
+            match reader.read(chunk) {
+                Ok(n) => return n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => { *error = Some(e); return 0; }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for NalReader<R> {
+    type Item = NAL;
+
+    fn next(&mut self) -> Option<NAL> {
+        let chunk = &mut self.chunk;
+        if self.parser.is_none() {
+            let mut probe = 0;
+            while probe < PROBE_SIZE {
+                let n = Self::read(&mut self.reader, &mut chunk[probe ..], &mut self.error);
+                if n == 0 { break; }
+                probe += n;
+            }
+            let format = *self.format.get_or_insert_with(|| StreamFormat::detect(&chunk[.. probe]));
+            let mut parser = format.parser();
+            self.pending.extend(parser.push(&chunk[.. probe]));
+            self.parser = Some(parser);
+        }
+        while self.pending.is_empty() && !self.done {
+            let n = Self::read(&mut self.reader, chunk, &mut self.error);
+            let parser = self.parser.as_mut().unwrap();
+            if n == 0 {
+                self.done = true;
+                self.pending.extend(parser.finish());
+            } else {
+                self.pending.extend(parser.push(&chunk[.. n]));
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+#[allow(dead_code)] // for mp4_parser, which main doesn't use
+pub fn nal_from_data(buf: &[u8]) -> Option<NAL> {
+    NAL::new(0, buf.len(), Bytes::from(buf))
+}
+
+// Groups of pictures of a stream, each with the SPS and PPS its pictures refer to. Groups are read
+// one at a time, only the NAL units of the returned group are kept in memory.
+pub struct GopReader<R: Read> {
+    access_units: AccessUnits<NalReader<R>>,
+    gops: GopBuilder,
+    pair: Option<(NAL, NAL, Option<NAL>)>, // SPS/PPS and SPS extension of the group being collected
+    done: bool,
+    waiting_for_idr: bool,          // resilient mode: access units are dropped until the next random access point
+    prev_ref_frame_num: Option<u32>, // resilient mode: PrevRefFrameNum, 7.4.3
+}
+
+impl GopReader<std::fs::File> {
+    pub fn open(path: &str, format: Option<StreamFormat>) -> std::io::Result<GopReader<std::fs::File>> {
+        Ok(GopReader::new(std::fs::File::open(path)?, format))
+    }
+}
+
+impl<R: Read> GopReader<R> {
+    pub fn new(reader: R, format: Option<StreamFormat>) -> GopReader<R> {
+        GopReader{
+            access_units: AccessUnits::new(NalReader::new(reader, format)), gops: GopBuilder::default(), pair: None, done: false,
+            waiting_for_idr: false, prev_ref_frame_num: None,
+        }
+    }
+
+    // drops corrupted NAL units and skips damaged pictures and the pictures predicted from them,
+    // up to the next IDR picture (or recovery point, some cameras never send IDR pictures)
+    pub fn resilient(mut self) -> GopReader<R> {
+        self.access_units.resilient = true;
+        self
+    }
+
+    // what the resilient mode dropped so far
+    pub fn corruptions(&self) -> &[Corruption] { &self.access_units.corruptions }
+
+    // the read error that ended the groups early
+    pub fn take_error(&mut self) -> Option<std::io::Error> { self.access_units.nals_mut().take_error() }
+
+    // the reason to drop the access unit in resilient mode
+    fn damage(&mut self, au: &AccessUnit) -> Option<Damage> {
+        let slice = match &au.slice {
+            Some(slice) => slice,
+            None => return if au.is_damaged { Some(Damage::DamagedPicture) } else { None }, // no picture, e.g. trailing SEI
+        };
+        if au.is_damaged || slice.first_mb_in_slice != 0 { return Some(Damage::DamagedPicture); }
+
+        // 7.4.3 frame_num is PrevRefFrameNum or the following value. Many cameras set
+        // gaps_in_frame_num_value_allowed_flag without ever skipping frame_num, a gap is a lost picture either way.
+        let sps = self.access_units.params().get_pair(slice.pic_parameter_set_id).map(|(sps, _)| &sps.parsed);
+        let gap = match (sps, self.prev_ref_frame_num) {
+            (Some(sps), Some(prev)) if !slice.idr_pic_flag => {
+                let max_frame_num = 1u32 << (sps.log2_max_frame_num_minus4 + 4);
+                slice.frame_num != prev && slice.frame_num != (prev + 1) % max_frame_num
+            }
+            _ => false,
+        };
+        if au.is_reference {
+            self.prev_ref_frame_num = Some(if slice.memory_management_control_operation_5 { 0 } else { slice.frame_num });
+        }
+        if gap { return Some(Damage::FrameNumGap); }
+
+        if au.is_random_access_point() { self.waiting_for_idr = false; }
+        if self.waiting_for_idr { return Some(Damage::WaitingForIdr); }
+        None
+    }
+
+    // pictures received before any SPS and PPS can't be decoded
+    fn complete(&self, mut idr: IDR) -> Option<IDR> {
+        match &self.pair {
+            Some((sps, pps, sps_ext)) => {
+                idr.sps = Some(sps.clone());
+                idr.pps = Some(pps.clone());
+                idr.sps_ext = sps_ext.clone();
+                Some(idr)
+            }
+            None => { eprintln!("Skipping {} access units without SPS/PPS", idr.samples.len()); None }
+        }
+    }
+}
+
+impl<R: Read> Iterator for GopReader<R> {
+    type Item = IDR;
+
+    fn next(&mut self) -> Option<IDR> {
+        while let Some(au) = self.access_units.next() {
+            if self.access_units.resilient {
+                if let Some(damage) = self.damage(&au) {
+                    self.access_units.corruptions.push(Corruption::of_access_unit(&au, damage));
+                    // nothing is predicted from a damaged non-reference picture
+                    if au.is_reference || damage != Damage::DamagedPicture { self.waiting_for_idr = true; }
+                    continue;
+                }
+            }
+            // the first picture of a group selects the SPS/PPS pair for its sample description
+            let starts_group = au.is_random_access_point() || self.pair.is_none();
+            let params = self.access_units.params();
+            let au_pair = au.slice.as_ref().and_then(|slice| params.get_pair(slice.pic_parameter_set_id))
+                .map(|(sps, pps)| (sps.nal.clone(), pps.nal.clone(), params.sps_ext.get(&sps.parsed.seq_parameter_set_id).cloned()));
+            let complete = self.gops.push(au).and_then(|idr| self.complete(idr));
+            if starts_group && au_pair.is_some() { self.pair = au_pair; }
+            if complete.is_some() { return complete; }
+        }
+        if self.done { return None; }
+        self.done = true;
+        self.gops.finish().and_then(|idr| self.complete(idr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbsp::{BitWriter, rbsp_to_ebsp};
+    use crate::testing::{STREAM, stream_nals};
+
+    fn slice(idr: bool, nal_ref_idc: u8, frame_num: u32, pic_order_cnt_lsb: u32) -> SliceHeader {
+        SliceHeader{idr_pic_flag: idr, nal_ref_idc, frame_num, pic_order_cnt_lsb, ..SliceHeader::default()}
+    }
+
+    fn pocs(sps: &Sps, slices: &[SliceHeader]) -> Vec<i32> {
+        let mut state = PocState::default();
+        slices.iter().map(|sh| state.compute(sh, sps)).collect()
+    }
+
+    #[test]
+    fn poc_type_0() {
+        // MaxPicOrderCntLsb 16: the lsb wraps after 14 and PicOrderCntMsb moves up
+        let sps = Sps{pic_order_cnt_type: 0, ..Sps::default()};
+        let slices = [slice(true, 3, 0, 0), slice(false, 2, 1, 8), slice(false, 0, 2, 4), slice(false, 2, 2, 14),
+            slice(false, 2, 3, 2), slice(false, 0, 4, 0)];
+        assert_eq!(pocs(&sps, &slices), vec![0, 8, 4, 14, 18, 16]);
+    }
+
+    #[test]
+    fn poc_type_1() {
+        let sps = Sps{pic_order_cnt_type: 1, offset_for_non_ref_pic: -1, offset_for_ref_frame: vec![2], ..Sps::default()};
+        let slices = [slice(true, 3, 0, 0), slice(false, 2, 1, 0), slice(false, 0, 2, 0), slice(false, 2, 2, 0)];
+        assert_eq!(pocs(&sps, &slices), vec![0, 2, 1, 4]);
+    }
+
+    #[test]
+    fn poc_type_2() {
+        // MaxFrameNum 16: FrameNumOffset grows when frame_num wraps
+        let sps = Sps{pic_order_cnt_type: 2, ..Sps::default()};
+        let slices = [slice(true, 3, 0, 0), slice(false, 2, 1, 0), slice(false, 0, 2, 0), slice(false, 2, 2, 0),
+            slice(false, 2, 15, 0), slice(false, 2, 0, 0)];
+        assert_eq!(pocs(&sps, &slices), vec![0, 2, 3, 4, 30, 32]);
+    }
+
+    fn push_in_chunks(data: &[u8], chunk_size: usize) -> Vec<NAL> {
+        let mut parser = AnnexBParser::new();
+        let mut nals = vec![];
+        for chunk in data.chunks(chunk_size) { nals.extend(parser.push(chunk)); }
+        nals.extend(parser.finish());
+        nals
+    }
+
+    #[test]
+    fn annexb_split_start_codes() {
+        // 4 and 3 byte start codes, trailing_zero_8bits and a last NAL unit without a following start code
+        let data = [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 0, 0, 1, 0x65, 4, 0, 0, 3, 5];
+        for chunk_size in 1 ..= data.len() {
+            let nals = push_in_chunks(&data, chunk_size);
+            let units: Vec<(usize, usize, &[u8])> = nals.iter().map(|nal| (nal.start, nal.end, &nal.data[..])).collect();
+            assert_eq!(units, vec![
+                (4, 7, &[0x67, 1, 2][..]),
+                (10, 12, &[0x68, 3][..]),
+                (18, 24, &[0x65, 4, 0, 0, 3, 5][..]),
+            ], "chunks of {} bytes", chunk_size);
+        }
+    }
+
+    #[test]
+    fn annexb_stream_chunks() {
+        let data = std::fs::read(STREAM).unwrap();
+        let whole = stream_nals();
+        assert_eq!(whole.last().unwrap().end, data.len());
+        for chunk_size in [1000, 4093, 65536] {
+            let nals = push_in_chunks(&data, chunk_size);
+            assert_eq!(nals.len(), whole.len());
+            for (nal, expected) in nals.iter().zip(&whole) {
+                assert_eq!((nal.start, nal.end, &nal.data), (expected.start, expected.end, &expected.data));
+            }
+        }
+    }
+
+    // a connection that drops after its data
+    struct DroppedConnection(std::io::Cursor<Vec<u8>>);
+
+    impl Read for DroppedConnection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset")),
+                n => Ok(n),
+            }
+        }
+    }
 
-    println!("count  {}", count);
+    #[test]
+    fn read_error() {
+        let data = std::fs::read(STREAM).unwrap();
+        let mut reader = NalReader::new(DroppedConnection(std::io::Cursor::new(data)), None);
+        assert_eq!(reader.by_ref().count(), stream_nals().len());
+        assert_eq!(reader.take_error().map(|e| e.kind()), Some(std::io::ErrorKind::ConnectionReset));
+    }
+
+    // recovery point with recovery_frame_cnt 3, user data with a UUID and a payloadType above 255
+    fn sei_nal() -> Vec<u8> {
+        let mut data = vec![0x06];
+        data.extend([SEI_RECOVERY_POINT as u8, 2, 0b0010_0100, 0b0100_0000]);
+        data.extend([SEI_USER_DATA_UNREGISTERED as u8, 19]);
+        data.extend(0x10 .. 0x20);
+        data.extend([0xAA, 0xBB, 0xCC]);
+        data.extend([0xFF, 0x01, 1, 0x42]);
+        data.push(0x80); // rbsp_trailing_bits
+        data
+    }
+
+    #[test]
+    fn sei_messages() {
+        let messages = parse_sei(&sei_nal(), &ParameterSets::default());
+        let types: Vec<(u32, u32)> = messages.iter().map(|m| (m.payload_type, m.payload_size)).collect();
+        assert_eq!(types, vec![(6, 2), (5, 19), (256, 1)]);
+        match &messages[0].payload {
+            SeiPayload::RecoveryPoint(rp) => {
+                assert_eq!((rp.recovery_frame_cnt, rp.exact_match_flag, rp.broken_link_flag, rp.changing_slice_group_idc), (3, true, false, 0));
+            }
+            payload => panic!("{:?}", payload),
+        }
+        match &messages[1].payload {
+            SeiPayload::UserDataUnregistered(ud) => {
+                assert_eq!(ud.uuid_iso_iec_11578.to_vec(), (0x10 .. 0x20).collect::<Vec<u8>>());
+                assert_eq!(ud.payload, vec![0xAA, 0xBB, 0xCC]);
+            }
+            payload => panic!("{:?}", payload),
+        }
+        assert!(matches!(&messages[2].payload, SeiPayload::Other(data) if data == &[0x42]));
+    }
 
-//    let h = buf.get_u8();
-//    println!("h: {:x?} ", h);
-//
-//
-//
-//    println!("{:x?} ", &bytes[0 .. 4]);
-//    println!("{:x?} ", &bytes[4 .. 4 + 4]);
+    #[test]
+    fn recovery_point_starts_group() {
+        // the parameter sets and P pictures of the stream, the second P picture preceded by the SEI
+        let nals = stream_nals();
+        let find = |unit_type: NalUnitType, n: usize| nals.iter().filter(|nal| nal.unit_type == unit_type).nth(n).unwrap().data.to_vec();
+        let units = [find(NalUnitType::SPS, 0), find(NalUnitType::PPS, 0), find(NalUnitType::CodedSliceNonIdr, 0),
+            sei_nal(), find(NalUnitType::CodedSliceNonIdr, 1)];
+        let mut data = vec![];
+        for unit in &units { data.extend([0, 0, 0, 1]); data.extend(unit); }
 
+        let access_units: Vec<AccessUnit> = AccessUnits::new(push_in_chunks(&data, data.len()).into_iter()).collect();
+        assert_eq!(access_units.len(), 2);
+        assert!(!access_units[0].is_random_access_point());
+        let au = &access_units[1];
+        assert!(au.is_random_access_point() && !au.is_idr);
+        assert_eq!(au.recovery_point().unwrap().recovery_frame_cnt, 3);
+        assert_eq!(au.user_data_unregistered().map(|ud| ud.payload.clone()).collect::<Vec<_>>(), vec![vec![0xAA, 0xBB, 0xCC]]);
+
+        let mut gops = GopBuilder::default();
+        assert!(gops.push(access_units[0].clone()).is_none());
+        assert_eq!(gops.push(access_units[1].clone()).unwrap().samples.len(), 1);
+    }
+
+    fn avcc(nals: &[NAL], length_size: u8) -> Vec<u8> {
+        let mut data = vec![];
+        for nal in nals {
+            data.extend(&(nal.data.len() as u32).to_be_bytes()[4 - length_size as usize ..]);
+            data.extend(&nal.data[..]);
+        }
+        data
+    }
 
-//    let mut cur = Cursor::new(contents);
-//    let h = cur.read_u32::<BigEndian>()?;
-//    println!("{:x?} ", h);
-//    let h = cur.read_u32::<BigEndian>()?;
+    #[test]
+    fn detect_stream_format() {
+        let annexb = std::fs::read(STREAM).unwrap();
+        assert_eq!(StreamFormat::detect(&annexb[.. PROBE_SIZE]), StreamFormat::AnnexB);
 
-    // let pos = cur.position() as usize;
+        // NAL units short enough for every length field size
+        let nals: Vec<NAL> = stream_nals().into_iter().filter(|nal| nal.data.len() < 256).take(12).collect();
+        assert_eq!(nals.len(), 12);
+        for length_size in [1, 2, 4] {
+            let data = avcc(&nals, length_size);
+            assert_eq!(StreamFormat::detect(&data), StreamFormat::Avcc(length_size));
 
-    Ok((idrs, sps, pps))
+            let mut parser = AvccParser::new(length_size);
+            let mut parsed = vec![];
+            for chunk in data.chunks(5) { parsed.extend(parser.push(chunk)); }
+            assert!(parser.finish().is_empty());
+            let parsed: Vec<&[u8]> = parsed.iter().map(|nal| &nal.data[..]).collect();
+            assert_eq!(parsed, nals.iter().map(|nal| &nal.data[..]).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn corrupt_nal_length() {
+        let nals: Vec<NAL> = stream_nals().into_iter().take(12).collect();
+        let mut data = avcc(&nals, 4);
+        // the length field of the sixth NAL unit
+        let pos: usize = nals[.. 5].iter().map(|nal| nal.data.len() + 4).sum();
+        data[pos .. pos + 4].copy_from_slice(&[0xff; 4]);
+
+        let mut parser = AvccParser::new(4);
+        let mut parsed = vec![];
+        for chunk in data.chunks(100) { parsed.extend(parser.push(chunk)); }
+        parsed.extend(parser.finish());
+        let parsed: Vec<&[u8]> = parsed.iter().map(|nal| &nal.data[..]).collect();
+        let expected: Vec<&[u8]> = nals.iter().enumerate().filter(|(i, _)| *i != 5).map(|(_, nal)| &nal.data[..]).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn parameter_set_change() {
+        // the first group of the stream twice, the second time with level_idc 40 in the SPS
+        let nals = stream_nals();
+        let second_sps = nals.iter().skip(1).position(|nal| nal.unit_type == NalUnitType::SPS).unwrap() + 1;
+        let mut data = vec![];
+        for level_idc in [42, 40] {
+            for nal in &nals[.. second_sps] {
+                let mut unit = nal.data.to_vec();
+                if nal.unit_type == NalUnitType::SPS { unit[3] = level_idc; }
+                data.extend([0, 0, 0, 1]);
+                data.extend(unit);
+            }
+        }
+        let groups: Vec<IDR> = GopReader::new(std::io::Cursor::new(data), None).collect();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].samples.len(), groups[1].samples.len());
+        let data = |nal: &Option<NAL>| nal.as_ref().unwrap().data.clone();
+        assert_eq!(data(&groups[1].pps), data(&groups[0].pps));
+        assert_ne!(data(&groups[1].sps), data(&groups[0].sps));
+        assert_eq!(groups[1].sps.as_ref().unwrap().data[3], 40);
+    }
+
+    #[test]
+    fn first_vcl_of_new_picture() {
+        let prev = slice(false, 2, 5, 10);
+        assert!(!slice(false, 2, 5, 10).is_first_vcl_of_new_picture(&prev));
+        assert!(!slice(false, 1, 5, 10).is_first_vcl_of_new_picture(&prev)); // nal_ref_idc differs, neither is 0
+        assert!(slice(false, 0, 5, 10).is_first_vcl_of_new_picture(&prev));
+        assert!(slice(false, 2, 6, 10).is_first_vcl_of_new_picture(&prev));
+        assert!(slice(false, 2, 5, 12).is_first_vcl_of_new_picture(&prev));
+        assert!(slice(true, 2, 5, 10).is_first_vcl_of_new_picture(&prev));
+        let idr = SliceHeader{idr_pic_id: 1, ..slice(true, 3, 0, 0)};
+        assert!(SliceHeader{idr_pic_id: 2, ..idr.clone()}.is_first_vcl_of_new_picture(&idr));
+        let mut second_slice = idr.clone();
+        second_slice.first_mb_in_slice = 100;
+        assert!(!second_slice.is_first_vcl_of_new_picture(&idr));
+    }
+
+    fn nal_unit(header: u8, mut w: BitWriter) -> Vec<u8> {
+        w.write_rbsp_trailing_bits();
+        let mut data = vec![0, 0, 0, 1, header];
+        data.extend(rbsp_to_ebsp(&w.into_bytes()));
+        data
+    }
+
+    // SPS of the repository stream, 1920x1080 in 120x68 macroblocks, with the given fields
+    fn stream_sps(log2_max_frame_num_minus4: u32, pic_order_cnt_type: u32, log2_max_pic_order_cnt_lsb_minus4: u32,
+                  pic_width_in_mbs_minus1: u32) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.write_bits(8, 66); w.write_bits(8, 0); w.write_bits(8, 42); w.write_ue(0); // profile_idc, constraint flags, level_idc, id
+        w.write_ue(log2_max_frame_num_minus4); w.write_ue(pic_order_cnt_type);
+        if pic_order_cnt_type == 0 { w.write_ue(log2_max_pic_order_cnt_lsb_minus4); }
+        w.write_ue(1); w.write_bit(false); // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+        w.write_ue(pic_width_in_mbs_minus1); w.write_ue(67);
+        w.write_bit(true); w.write_bit(true); // frame_mbs_only_flag, direct_8x8_inference_flag
+        w.write_bit(true); w.write_ue(0); w.write_ue(0); w.write_ue(0); w.write_ue(4); w.write_bit(false); // cropping to 1080 lines, no VUI
+        nal_unit(0x67, w)
+    }
+
+    #[test]
+    fn corrupted_sps() {
+        let sps = Sps::parse(&stream_sps(6, 2, 0, 119)[4 ..]).unwrap();
+        assert_eq!((sps.width(), sps.height()), (1920, 1080));
+        assert!(Sps::parse(&stream_sps(12, 0, 12, 119)[4 ..]).is_some());
+        for data in [stream_sps(13, 2, 0, 119), stream_sps(6, 3, 0, 119), stream_sps(6, 0, 13, 119), stream_sps(6, 2, 0, 0x1000_0000)] {
+            assert!(Sps::parse(&data[4 ..]).is_none());
+        }
+
+        // the SPS of the second group claims 32 bit frame_num values, its pictures keep the SPS of the first group
+        let nals = stream_nals();
+        let second_sps = nals.iter().skip(1).position(|nal| nal.unit_type == NalUnitType::SPS).unwrap() + 1;
+        let mut data = vec![];
+        for (i, nal) in nals.iter().enumerate() {
+            if i == second_sps {
+                data.extend(stream_sps(28, 2, 0, 119));
+            } else {
+                data.extend([0, 0, 0, 1]);
+                data.extend(&nal.data[..]);
+            }
+        }
+        let mut groups = GopReader::new(std::io::Cursor::new(data), None).resilient();
+        assert_eq!(groups.by_ref().map(|idr| idr.samples.len()).sum::<usize>(), 235);
+        assert!(groups.corruptions().is_empty());
+    }
+
+    // PAFF stream of 32x32 field pairs, the top field of the first pair is the IDR picture.
+    // missing is a pair without its bottom field.
+    fn paff_stream(pairs: u32, missing: Option<u32>) -> Vec<u8> {
+        let mut sps = BitWriter::new();
+        sps.write_bits(8, 77); sps.write_bits(8, 0); sps.write_bits(8, 40); // profile_idc, constraint flags, level_idc
+        sps.write_ue(0); sps.write_ue(0); sps.write_ue(0); sps.write_ue(0); // id, log2_max_frame_num_minus4, poc type 0, log2_max_pic_order_cnt_lsb_minus4
+        sps.write_ue(1); sps.write_bit(false); // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+        sps.write_ue(1); sps.write_ue(0); // pic_width_in_mbs_minus1, pic_height_in_map_units_minus1
+        sps.write_bit(false); sps.write_bit(false); // frame_mbs_only_flag, mb_adaptive_frame_field_flag
+        sps.write_bit(true); sps.write_bit(false); sps.write_bit(false); // direct_8x8_inference_flag, no cropping, no VUI
+        let mut pps = BitWriter::new();
+        pps.write_ue(0); pps.write_ue(0); pps.write_bit(false); pps.write_bit(false); // ids, CAVLC, no bottom_field_pic_order_in_frame_present_flag
+        pps.write_ue(0); pps.write_ue(0); pps.write_ue(0); // one slice group, num_ref_idx defaults
+        pps.write_bit(false); pps.write_bits(2, 0); pps.write_se(0); pps.write_se(0); pps.write_se(0);
+        pps.write_bit(false); pps.write_bit(false); pps.write_bit(false);
+
+        let mut data = nal_unit(0x67, sps);
+        data.extend(nal_unit(0x68, pps));
+        for frame in 0 .. pairs {
+            for bottom in [false, true] {
+                if bottom && missing == Some(frame) { continue; }
+                let idr = frame == 0 && !bottom;
+                let mut slice = BitWriter::new();
+                slice.write_ue(0); slice.write_ue(if idr { 7 } else { 5 }); slice.write_ue(0); // first_mb_in_slice, slice_type, pps id
+                slice.write_bits(4, frame % 16); slice.write_bit(true); slice.write_bit(bottom); // frame_num, field_pic_flag, bottom_field_flag
+                if idr { slice.write_ue(0); } // idr_pic_id
+                slice.write_bits(4, (2 * frame + bottom as u32) % 16); // pic_order_cnt_lsb
+                if !idr { slice.write_bit(false); slice.write_bit(false); } // num_ref_idx_active_override_flag, ref_pic_list_modification_flag_l0
+                if idr { slice.write_bit(false); slice.write_bit(false); } else { slice.write_bit(false); } // dec_ref_pic_marking()
+                slice.write_se(0); slice.write_bits(16, 0xABCD); // slice_qp_delta, slice data
+                data.extend(nal_unit(if idr { 0x65 } else { 0x61 }, slice));
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn field_pairs() {
+        let data = paff_stream(4, None);
+        let access_units: Vec<AccessUnit> = AccessUnits::new(push_in_chunks(&data, data.len()).into_iter()).collect();
+        assert_eq!(access_units.len(), 4);
+        for (i, au) in access_units.iter().enumerate() {
+            assert_eq!(au.nals.iter().filter(|nal| nal.unit_type.is_vcl()).count(), 2);
+            let sh = au.slice.as_ref().unwrap();
+            assert_eq!((sh.frame_num, sh.bottom_field_flag), (i as u32, false));
+            assert_eq!(au.poc, 2 * i as i32); // the smaller PicOrderCnt of the pair
+            assert_eq!(au.index, i);
+        }
+        assert!(access_units[0].is_idr && !access_units[1].is_idr);
+
+        let sps = Sps::parse(&access_units[0].nals[0].data).unwrap();
+        assert_eq!((sps.width(), sps.height()), (32, 32));
+    }
+
+    #[test]
+    fn unpaired_field() {
+        // the bottom field of the third pair is missing, its top field is a sample of its own
+        let data = paff_stream(4, Some(2));
+        let access_units: Vec<AccessUnit> = AccessUnits::new(push_in_chunks(&data, data.len()).into_iter()).collect();
+        let fields: Vec<usize> = access_units.iter().map(|au| au.nals.iter().filter(|nal| nal.unit_type.is_vcl()).count()).collect();
+        assert_eq!(fields, vec![2, 2, 1, 2]);
+        let pocs: Vec<i32> = access_units.iter().map(|au| au.poc).collect();
+        assert_eq!(pocs, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn unparsable_slice_header() {
+        // a slice of an unknown PPS between two field pairs is an access unit of its own
+        let mut slice = BitWriter::new();
+        slice.write_ue(0); slice.write_ue(5); slice.write_ue(5); // first_mb_in_slice, slice_type, pic_parameter_set_id
+        let mut nals = push_in_chunks(&paff_stream(2, None), usize::MAX);
+        nals.insert(4, push_in_chunks(&nal_unit(0x61, slice), usize::MAX).remove(0));
+        let access_units: Vec<AccessUnit> = AccessUnits::new(nals.into_iter()).collect();
+        let fields: Vec<(usize, bool)> = access_units.iter()
+            .map(|au| (au.nals.iter().filter(|nal| nal.unit_type.is_vcl()).count(), au.is_damaged))
+            .collect();
+        assert_eq!(fields, vec![(2, false), (1, true), (2, false)]);
+
+        assert_eq!(SliceType::from_u32(7), Some(SliceType::I));
+        assert_eq!(SliceType::from_u32(10), None);
+    }
+
+    #[test]
+    fn stream_access_units() {
+        // 235 single slice pictures, the parameter sets and SEI belong to the access unit of the following IDR picture
+        let access_units: Vec<AccessUnit> = AccessUnits::new(stream_nals().into_iter()).collect();
+        assert_eq!(access_units.len(), 235);
+        for au in &access_units {
+            assert_eq!(au.nals.iter().filter(|nal| nal.unit_type.is_vcl()).count(), 1);
+            assert!(au.nals.last().unwrap().unit_type.is_vcl());
+            let types: Vec<NalUnitType> = au.nals.iter().map(|nal| nal.unit_type).collect();
+            if au.is_idr {
+                assert_eq!(types, vec![NalUnitType::SPS, NalUnitType::PPS, NalUnitType::SEI, NalUnitType::CodedSliceIdr]);
+            }
+        }
+        assert_eq!(access_units.iter().filter(|au| au.is_idr).count(), 8);
+        let pocs: Vec<i32> = access_units[.. 3].iter().map(|au| au.poc).collect();
+        assert_eq!(pocs, vec![0, 2, 4]);
+    }
 }
@@ -0,0 +1,777 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use bytes::Bytes;
+
+use crate::h264::{self, NalReader, StreamFormat};
+use crate::rbsp::{BitReader, ebsp_to_rbsp};
+
+// Group of access units starting at an IRAP picture
+pub struct Gop {
+    pub samples: Vec<AccessUnit>,
+}
+
+impl Gop {
+    // the VPS, SPS and PPS of the first picture
+    pub fn parameter_sets(&self) -> Option<&(NAL, NAL, NAL)> {
+        self.samples.first()?.parameter_sets.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessUnit {
+    pub nals: Vec<NAL>,
+    pub is_irap: bool,
+    pub poc: i32,
+    pub parameter_sets: Option<(NAL, NAL, NAL)>, // VPS, SPS and PPS active for the picture
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum NalUnitType { //   Table 7-1 NAL unit type codes and NAL unit type classes
+    TrailN,         // 0 Coded slice segment of a non-TSA, non-STSA trailing picture
+    TrailR,         // 1
+    TsaN,           // 2 Coded slice segment of a TSA picture
+    TsaR,           // 3
+    StsaN,          // 4 Coded slice segment of an STSA picture
+    StsaR,          // 5
+    RadlN,          // 6 Coded slice segment of a RADL picture
+    RadlR,          // 7
+    RaslN,          // 8 Coded slice segment of a RASL picture
+    RaslR,          // 9
+        // 10..15   // Reserved non-IRAP VCL
+    BlaWLp,         // 16 Coded slice segment of a BLA picture
+    BlaWRadl,       // 17
+    BlaNLp,         // 18
+    IdrWRadl,       // 19 Coded slice segment of an IDR picture
+    IdrNLp,         // 20
+    CraNut,         // 21 Coded slice segment of a CRA picture
+        // 22..23   // Reserved IRAP VCL
+        // 24..31   // Reserved non-IRAP VCL
+    VPS,            // 32 Video parameter set
+    SPS,            // 33 Sequence parameter set
+    PPS,            // 34 Picture parameter set
+    AUD,            // 35 Access unit delimiter
+    EOS,            // 36 End of sequence
+    EOB,            // 37 End of bitstream
+    FD,             // 38 Filler data
+    PrefixSEI,      // 39 Supplemental enhancement information
+    SuffixSEI,      // 40
+        // 41..47   // Reserved
+        // 48..63   // Unspecified
+    Other(u8),      // reserved and unspecified values
+}
+
+impl NalUnitType {
+    pub fn from_u8(v: u8) -> NalUnitType {
+        match v {
+            0  => NalUnitType::TrailN,
+            1  => NalUnitType::TrailR,
+            2  => NalUnitType::TsaN,
+            3  => NalUnitType::TsaR,
+            4  => NalUnitType::StsaN,
+            5  => NalUnitType::StsaR,
+            6  => NalUnitType::RadlN,
+            7  => NalUnitType::RadlR,
+            8  => NalUnitType::RaslN,
+            9  => NalUnitType::RaslR,
+            16 => NalUnitType::BlaWLp,
+            17 => NalUnitType::BlaWRadl,
+            18 => NalUnitType::BlaNLp,
+            19 => NalUnitType::IdrWRadl,
+            20 => NalUnitType::IdrNLp,
+            21 => NalUnitType::CraNut,
+            32 => NalUnitType::VPS,
+            33 => NalUnitType::SPS,
+            34 => NalUnitType::PPS,
+            35 => NalUnitType::AUD,
+            36 => NalUnitType::EOS,
+            37 => NalUnitType::EOB,
+            38 => NalUnitType::FD,
+            39 => NalUnitType::PrefixSEI,
+            40 => NalUnitType::SuffixSEI,
+            _  => NalUnitType::Other(v),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            NalUnitType::TrailN => 0,
+            NalUnitType::TrailR => 1,
+            NalUnitType::TsaN => 2,
+            NalUnitType::TsaR => 3,
+            NalUnitType::StsaN => 4,
+            NalUnitType::StsaR => 5,
+            NalUnitType::RadlN => 6,
+            NalUnitType::RadlR => 7,
+            NalUnitType::RaslN => 8,
+            NalUnitType::RaslR => 9,
+            NalUnitType::BlaWLp => 16,
+            NalUnitType::BlaWRadl => 17,
+            NalUnitType::BlaNLp => 18,
+            NalUnitType::IdrWRadl => 19,
+            NalUnitType::IdrNLp => 20,
+            NalUnitType::CraNut => 21,
+            NalUnitType::VPS => 32,
+            NalUnitType::SPS => 33,
+            NalUnitType::PPS => 34,
+            NalUnitType::AUD => 35,
+            NalUnitType::EOS => 36,
+            NalUnitType::EOB => 37,
+            NalUnitType::FD => 38,
+            NalUnitType::PrefixSEI => 39,
+            NalUnitType::SuffixSEI => 40,
+            NalUnitType::Other(v) => v,
+        }
+    }
+
+    pub fn is_vcl(self) -> bool { self.to_u8() < 32 }
+
+    // intra random access point: BLA, IDR, CRA and the reserved IRAP types
+    pub fn is_irap(self) -> bool { (16 ..= 23).contains(&self.to_u8()) }
+
+    pub fn is_idr(self) -> bool { matches!(self, NalUnitType::IdrWRadl | NalUnitType::IdrNLp) }
+
+    pub fn is_bla(self) -> bool { matches!(self, NalUnitType::BlaWLp | NalUnitType::BlaWRadl | NalUnitType::BlaNLp) }
+
+    // RADL and RASL pictures are not used as reference by trailing pictures
+    pub fn is_leading(self) -> bool { (6 ..= 9).contains(&self.to_u8()) }
+
+    // TRAIL_N, TSA_N, STSA_N, RADL_N, RASL_N and RSV_VCL_N10/12/14
+    pub fn is_sub_layer_non_reference(self) -> bool {
+        let v = self.to_u8();
+        v <= 14 && v.is_multiple_of(2)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct NAL {
+    pub start: usize,
+    pub unit_type: NalUnitType,
+    pub temporal_id: u8,    // nuh_temporal_id_plus1 - 1
+    pub data: Bytes,
+}
+
+impl NAL {
+    // data is the NAL unit without start code, beginning with the two byte NAL header (7.3.1.2)
+    pub fn new(start: usize, data: Bytes) -> Option<NAL> {
+        let (b0, b1) = (*data.first()?, *data.get(1)?);
+        Some(NAL{
+            start,
+            unit_type: NalUnitType::from_u8((b0 >> 1) & 0x3F),
+            temporal_id: (b1 & 0x07).saturating_sub(1),
+            data,
+        })
+    }
+
+    // the stream readers split NAL units without looking at the header, which they read as H.264
+    pub fn from_h264(nal: h264::NAL) -> Option<NAL> {
+        NAL::new(nal.start, nal.data)
+    }
+
+    fn rbsp(&self) -> Vec<u8> {
+        ebsp_to_rbsp(&self.data[2 ..])
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProfileTierLevel { // 7.3.3 Profile, tier and level syntax, general part only
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    pub general_constraint_indicator_flags: u64,    // 48 bits from general_progressive_source_flag on
+    pub general_level_idc: u8,
+}
+
+impl ProfileTierLevel {
+    fn parse(r: &mut BitReader, profile_present_flag: bool, max_num_sub_layers_minus1: u32) -> Option<ProfileTierLevel> {
+        let mut ptl = ProfileTierLevel::default();
+        if profile_present_flag {
+            ptl.general_profile_space = r.read_bits(2)? as u8;
+            ptl.general_tier_flag = r.read_bit()?;
+            ptl.general_profile_idc = r.read_bits(5)? as u8;
+            ptl.general_profile_compatibility_flags = r.read_bits(32)?;
+            ptl.general_constraint_indicator_flags = ((r.read_bits(32)? as u64) << 16) | r.read_bits(16)? as u64;
+        }
+        ptl.general_level_idc = r.read_bits(8)? as u8;
+        let mut sub_layers = vec![];
+        for _ in 0 .. max_num_sub_layers_minus1 {
+            let sub_layer_profile_present_flag = r.read_bit()?;
+            let sub_layer_level_present_flag = r.read_bit()?;
+            sub_layers.push((sub_layer_profile_present_flag, sub_layer_level_present_flag));
+        }
+        if max_num_sub_layers_minus1 > 0 {
+            r.skip_bits(2 * (8 - max_num_sub_layers_minus1 as usize))?; // reserved_zero_2bits
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present { r.skip_bits(88)?; } // sub_layer profile_space .. reserved bits
+            if level_present { r.skip_bits(8)?; } // sub_layer_level_idc
+        }
+        Some(ptl)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Vps { // 7.3.2.1 Video parameter set RBSP syntax, up to the timing info
+    pub vps_video_parameter_set_id: u32,
+    pub vps_max_layers_minus1: u32,
+    pub vps_max_sub_layers_minus1: u32,
+    pub vps_temporal_id_nesting_flag: bool,
+    pub profile_tier_level: ProfileTierLevel,
+    pub vps_timing_info_present_flag: bool,
+    pub vps_num_units_in_tick: u32,
+    pub vps_time_scale: u32,
+}
+
+impl Vps {
+    // data is the NAL unit without start code, including the two byte NAL header
+    pub fn parse(data: &[u8]) -> Option<Vps> {
+        let mut vps = Vps::default();
+        let rbsp = ebsp_to_rbsp(data.get(2 ..)?);
+        let mut r = BitReader::new(&rbsp);
+        vps.vps_video_parameter_set_id = r.read_bits(4)?;
+        r.read_bit()?; // vps_base_layer_internal_flag
+        r.read_bit()?; // vps_base_layer_available_flag
+        vps.vps_max_layers_minus1 = r.read_bits(6)?;
+        vps.vps_max_sub_layers_minus1 = r.read_bits(3)?;
+        vps.vps_temporal_id_nesting_flag = r.read_bit()?;
+        r.read_bits(16)?; // vps_reserved_0xffff_16bits
+        vps.profile_tier_level = ProfileTierLevel::parse(&mut r, true, vps.vps_max_sub_layers_minus1)?;
+        let vps_sub_layer_ordering_info_present_flag = r.read_bit()?;
+        let first = if vps_sub_layer_ordering_info_present_flag { 0 } else { vps.vps_max_sub_layers_minus1 };
+        for _ in first ..= vps.vps_max_sub_layers_minus1 {
+            r.read_ue()?; // vps_max_dec_pic_buffering_minus1
+            r.read_ue()?; // vps_max_num_reorder_pics
+            r.read_ue()?; // vps_max_latency_increase_plus1
+        }
+        let vps_max_layer_id = r.read_bits(6)? as usize;
+        let vps_num_layer_sets_minus1 = r.read_ue()? as usize;
+        r.skip_bits(vps_num_layer_sets_minus1 * (vps_max_layer_id + 1))?; // layer_id_included_flag
+        vps.vps_timing_info_present_flag = r.read_bit()?;
+        if vps.vps_timing_info_present_flag {
+            vps.vps_num_units_in_tick = r.read_bits(32)?;
+            vps.vps_time_scale = r.read_bits(32)?;
+        }
+        Some(vps)
+    }
+
+    // unlike H.264, one clock tick of the HEVC timing info is one picture
+    pub fn frame_rate(&self) -> Option<h264::FrameRate> {
+        if !self.vps_timing_info_present_flag || self.vps_num_units_in_tick == 0 || self.vps_time_scale == 0 { return None; }
+        Some(h264::FrameRate{timescale: self.vps_time_scale, sample_duration: self.vps_num_units_in_tick})
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sps { // 7.3.2.2 Sequence parameter set RBSP syntax, up to log2_max_pic_order_cnt_lsb_minus4
+    pub sps_video_parameter_set_id: u32,
+    pub sps_max_sub_layers_minus1: u32,
+    pub sps_temporal_id_nesting_flag: bool,
+    pub profile_tier_level: ProfileTierLevel,
+    pub sps_seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    pub conformance_window_flag: bool,
+    pub conf_win_left_offset: u32,
+    pub conf_win_right_offset: u32,
+    pub conf_win_top_offset: u32,
+    pub conf_win_bottom_offset: u32,
+    pub bit_depth_luma_minus8: u32,
+    pub bit_depth_chroma_minus8: u32,
+    pub log2_max_pic_order_cnt_lsb_minus4: u32,
+}
+
+impl Sps {
+    // data is the NAL unit without start code, including the two byte NAL header
+    pub fn parse(data: &[u8]) -> Option<Sps> {
+        let mut sps = Sps::default();
+        let rbsp = ebsp_to_rbsp(data.get(2 ..)?);
+        let mut r = BitReader::new(&rbsp);
+        sps.sps_video_parameter_set_id = r.read_bits(4)?;
+        sps.sps_max_sub_layers_minus1 = r.read_bits(3)?;
+        sps.sps_temporal_id_nesting_flag = r.read_bit()?;
+        sps.profile_tier_level = ProfileTierLevel::parse(&mut r, true, sps.sps_max_sub_layers_minus1)?;
+        sps.sps_seq_parameter_set_id = r.read_ue()?;
+        sps.chroma_format_idc = r.read_ue()?;
+        if sps.chroma_format_idc == 3 {
+            sps.separate_colour_plane_flag = r.read_bit()?;
+        }
+        sps.pic_width_in_luma_samples = r.read_ue()?;
+        sps.pic_height_in_luma_samples = r.read_ue()?;
+        sps.conformance_window_flag = r.read_bit()?;
+        if sps.conformance_window_flag {
+            sps.conf_win_left_offset = r.read_ue()?;
+            sps.conf_win_right_offset = r.read_ue()?;
+            sps.conf_win_top_offset = r.read_ue()?;
+            sps.conf_win_bottom_offset = r.read_ue()?;
+        }
+        sps.bit_depth_luma_minus8 = r.read_ue()?;
+        sps.bit_depth_chroma_minus8 = r.read_ue()?;
+        sps.log2_max_pic_order_cnt_lsb_minus4 = r.read_ue()?;
+        if sps.log2_max_pic_order_cnt_lsb_minus4 > 12 { return None; } // 7.4.3.2.1
+        Some(sps)
+    }
+
+    // SubWidthC, SubHeightC, Table 6-1
+    fn sub_sampling(&self) -> (u32, u32) {
+        match (self.chroma_format_idc, self.separate_colour_plane_flag) {
+            (1, false) => (2, 2),
+            (2, false) => (2, 1),
+            _ => (1, 1),
+        }
+    }
+
+    // width of the decoded picture after the conformance cropping window
+    pub fn width(&self) -> u32 {
+        let (sub_width_c, _) = self.sub_sampling();
+        let crop = sub_width_c * (self.conf_win_left_offset + self.conf_win_right_offset);
+        self.pic_width_in_luma_samples.saturating_sub(crop)
+    }
+
+    pub fn height(&self) -> u32 {
+        let (_, sub_height_c) = self.sub_sampling();
+        let crop = sub_height_c * (self.conf_win_top_offset + self.conf_win_bottom_offset);
+        self.pic_height_in_luma_samples.saturating_sub(crop)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pps { // 7.3.2.3 Picture parameter set RBSP syntax, the fields the slice segment header depends on
+    pub pps_pic_parameter_set_id: u32,
+    pub pps_seq_parameter_set_id: u32,
+    pub dependent_slice_segments_enabled_flag: bool,
+    pub output_flag_present_flag: bool,
+    pub num_extra_slice_header_bits: u32,
+}
+
+impl Pps {
+    pub fn parse(data: &[u8]) -> Option<Pps> {
+        let mut pps = Pps::default();
+        let rbsp = ebsp_to_rbsp(data.get(2 ..)?);
+        let mut r = BitReader::new(&rbsp);
+        pps.pps_pic_parameter_set_id = r.read_ue()?;
+        pps.pps_seq_parameter_set_id = r.read_ue()?;
+        pps.dependent_slice_segments_enabled_flag = r.read_bit()?;
+        pps.output_flag_present_flag = r.read_bit()?;
+        pps.num_extra_slice_header_bits = r.read_bits(3)?;
+        Some(pps)
+    }
+}
+
+pub struct ParameterSet<T> {
+    pub parsed: T,
+    pub nal: NAL,
+}
+
+#[derive(Default)]
+pub struct ParameterSets {
+    pub vps: HashMap<u32, ParameterSet<Vps>>,
+    pub sps: HashMap<u32, ParameterSet<Sps>>,
+    pub pps: HashMap<u32, ParameterSet<Pps>>,
+}
+
+impl ParameterSets {
+    // stores the NAL if it is a VPS, SPS or PPS, returns false for any other NAL unit
+    pub fn insert(&mut self, nal: &NAL) -> bool {
+        match nal.unit_type {
+            NalUnitType::VPS => {
+                if let Some(vps) = Vps::parse(&nal.data) {
+                    self.vps.insert(vps.vps_video_parameter_set_id, ParameterSet{parsed: vps, nal: nal.clone()});
+                }
+                true
+            }
+            NalUnitType::SPS => {
+                if let Some(sps) = Sps::parse(&nal.data) {
+                    self.sps.insert(sps.sps_seq_parameter_set_id, ParameterSet{parsed: sps, nal: nal.clone()});
+                }
+                true
+            }
+            NalUnitType::PPS => {
+                if let Some(pps) = Pps::parse(&nal.data) {
+                    self.pps.insert(pps.pps_pic_parameter_set_id, ParameterSet{parsed: pps, nal: nal.clone()});
+                }
+                true
+            }
+            _ => false
+        }
+    }
+
+    // the PPS with the given id and the SPS and VPS it refers to
+    pub fn get(&self, pic_parameter_set_id: u32) -> Option<(&ParameterSet<Vps>, &ParameterSet<Sps>, &ParameterSet<Pps>)> {
+        let pps = self.pps.get(&pic_parameter_set_id)?;
+        let sps = self.sps.get(&pps.parsed.pps_seq_parameter_set_id)?;
+        let vps = self.vps.get(&sps.parsed.sps_video_parameter_set_id)?;
+        Some((vps, sps, pps))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SliceSegmentHeader { // 7.3.6.1, up to slice_pic_order_cnt_lsb of the first segment of a picture
+    pub first_slice_segment_in_pic_flag: bool,
+    pub no_output_of_prior_pics_flag: bool,
+    pub slice_pic_parameter_set_id: u32,
+    pub slice_type: u32,
+    pub pic_output_flag: bool,
+    pub slice_pic_order_cnt_lsb: u32,
+}
+
+impl SliceSegmentHeader {
+    // the following segments of a picture are only parsed up to the PPS id,
+    // their slice_segment_address length depends on the SPS coding tree block size
+    pub fn parse(nal: &NAL, params: &ParameterSets) -> Option<SliceSegmentHeader> {
+        let mut sh = SliceSegmentHeader{pic_output_flag: true, ..Default::default()};
+        let rbsp = nal.rbsp();
+        let mut r = BitReader::new(&rbsp);
+        sh.first_slice_segment_in_pic_flag = r.read_bit()?;
+        if nal.unit_type.is_irap() {
+            sh.no_output_of_prior_pics_flag = r.read_bit()?;
+        }
+        sh.slice_pic_parameter_set_id = r.read_ue()?;
+        if !sh.first_slice_segment_in_pic_flag { return Some(sh); }
+
+        let (_, sps, pps) = params.get(sh.slice_pic_parameter_set_id)?;
+        let (sps, pps) = (&sps.parsed, &pps.parsed);
+        r.skip_bits(pps.num_extra_slice_header_bits as usize)?; // slice_reserved_flag
+        sh.slice_type = r.read_ue()?;
+        if pps.output_flag_present_flag {
+            sh.pic_output_flag = r.read_bit()?;
+        }
+        if sps.separate_colour_plane_flag {
+            r.read_bits(2)?; // colour_plane_id
+        }
+        if !nal.unit_type.is_idr() {
+            sh.slice_pic_order_cnt_lsb = r.read_bits(sps.log2_max_pic_order_cnt_lsb_minus4 as u8 + 4)?;
+        }
+        Some(sh)
+    }
+}
+
+// 8.3.1 Decoding process for picture order count
+#[derive(Default)]
+pub struct PocState {
+    prev_tid0_poc: i32,
+    started: bool,  // a CRA picture that starts the stream or follows an end of sequence has NoRaslOutputFlag = 1
+}
+
+impl PocState {
+    pub fn compute(&mut self, nal: &NAL, sh: &SliceSegmentHeader, sps: &Sps) -> i32 {
+        let max_poc_lsb = 1i32 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
+        let lsb = sh.slice_pic_order_cnt_lsb as i32;
+        let unit_type = nal.unit_type;
+        let no_rasl_output = unit_type.is_idr() || unit_type.is_bla() || (unit_type.is_irap() && !self.started);
+        let msb = if no_rasl_output {
+            0
+        } else {
+            let prev_lsb = self.prev_tid0_poc & (max_poc_lsb - 1);
+            let prev_msb = self.prev_tid0_poc - prev_lsb;
+            if lsb < prev_lsb && prev_lsb - lsb >= max_poc_lsb / 2 {
+                prev_msb + max_poc_lsb
+            } else if lsb > prev_lsb && lsb - prev_lsb > max_poc_lsb / 2 {
+                prev_msb - max_poc_lsb
+            } else {
+                prev_msb
+            }
+        };
+        let poc = msb + lsb;
+        if nal.temporal_id == 0 && !unit_type.is_leading() && !unit_type.is_sub_layer_non_reference() {
+            self.prev_tid0_poc = poc;
+        }
+        self.started = true;
+        poc
+    }
+
+    pub fn end_of_sequence(&mut self) { self.started = false; }
+}
+
+// 7.4.2.4.4 Order of NAL units and coded pictures and their association to access units
+#[derive(Default)]
+pub struct AccessUnitBuilder {
+    current: AccessUnit,
+    has_vcl: bool,
+    poc: PocState,
+}
+
+impl AccessUnitBuilder {
+    // returns the previous access unit when the NAL unit starts a new one
+    pub fn push(&mut self, nal: NAL, params: &ParameterSets) -> Option<AccessUnit> {
+        let starts_au = match nal.unit_type {
+            NalUnitType::AUD | NalUnitType::VPS | NalUnitType::SPS | NalUnitType::PPS | NalUnitType::PrefixSEI => true,
+            NalUnitType::Other(v) => matches!(v, 41 ..= 44 | 48 ..= 55),
+            t if t.is_vcl() => nal.data.get(2).is_some_and(|b| b & 0x80 != 0), // first_slice_segment_in_pic_flag
+            _ => false,
+        };
+        let mut complete = None;
+        if starts_au && self.has_vcl { complete = self.flush(); }
+
+        if nal.unit_type.is_vcl() && !self.has_vcl {
+            self.has_vcl = true;
+            self.current.is_irap = nal.unit_type.is_irap();
+            match SliceSegmentHeader::parse(&nal, params) {
+                Some(sh) => {
+                    if let Some((vps, sps, pps)) = params.get(sh.slice_pic_parameter_set_id) {
+                        self.current.poc = self.poc.compute(&nal, &sh, &sps.parsed);
+                        self.current.parameter_sets = Some((vps.nal.clone(), sps.nal.clone(), pps.nal.clone()));
+                    }
+                }
+                None => eprintln!("Can't parse slice segment header at {}", nal.start),
+            }
+        }
+        if nal.unit_type == NalUnitType::EOS { self.poc.end_of_sequence(); }
+        self.current.nals.push(nal);
+        complete
+    }
+
+    pub fn flush(&mut self) -> Option<AccessUnit> {
+        self.has_vcl = false;
+        if self.current.nals.is_empty() { return None; }
+        Some(std::mem::take(&mut self.current))
+    }
+}
+
+pub struct AccessUnits<I: Iterator<Item=NAL>> {
+    nals: I,
+    builder: AccessUnitBuilder,
+    params: ParameterSets,
+}
+
+impl<I: Iterator<Item=NAL>> AccessUnits<I> {
+    pub fn new(nals: I) -> AccessUnits<I> {
+        AccessUnits{nals, builder: AccessUnitBuilder::default(), params: ParameterSets::default()}
+    }
+}
+
+impl<I: Iterator<Item=NAL>> Iterator for AccessUnits<I> {
+    type Item = AccessUnit;
+
+    fn next(&mut self) -> Option<AccessUnit> {
+        for nal in &mut self.nals {
+            self.params.insert(&nal);
+            if let Some(au) = self.builder.push(nal, &self.params) { return Some(au); }
+        }
+        self.builder.flush()
+    }
+}
+
+pub fn composition_offsets(samples: &[AccessUnit]) -> Vec<i32> {
+    let pocs: Vec<i32> = samples.iter().map(|au| au.poc).collect();
+    h264::poc_composition_offsets(&pocs)
+}
+
+// H.265 NAL units of the units split by the H.264 reader
+struct Nals<R: Read>(NalReader<R>);
+
+impl<R: Read> Iterator for Nals<R> {
+    type Item = NAL;
+
+    fn next(&mut self) -> Option<NAL> {
+        self.0.by_ref().find_map(NAL::from_h264)
+    }
+}
+
+// Groups of pictures of a stream, read one at a time so only the access units of the returned group
+// are kept in memory
+pub struct GopReader<R: Read> {
+    access_units: AccessUnits<Nals<R>>,
+    next: Option<AccessUnit>,   // IRAP picture starting the next group
+}
+
+impl GopReader<std::fs::File> {
+    pub fn open(path: &str, format: Option<StreamFormat>) -> std::io::Result<GopReader<std::fs::File>> {
+        Ok(GopReader::new(std::fs::File::open(path)?, format))
+    }
+}
+
+impl<R: Read> GopReader<R> {
+    pub fn new(reader: R, format: Option<StreamFormat>) -> GopReader<R> {
+        GopReader{access_units: AccessUnits::new(Nals(NalReader::new(reader, format))), next: None}
+    }
+
+    // the read error that ended the groups early
+    pub fn take_error(&mut self) -> Option<std::io::Error> { self.access_units.nals.0.take_error() }
+}
+
+impl<R: Read> Iterator for GopReader<R> {
+    type Item = Gop;
+
+    fn next(&mut self) -> Option<Gop> {
+        let mut samples: Vec<AccessUnit> = self.next.take().into_iter().collect();
+        for au in self.access_units.by_ref() {
+            if au.is_irap && !samples.is_empty() {
+                self.next = Some(au);
+                return Some(Gop{samples});
+            }
+            samples.push(au);
+        }
+        if samples.is_empty() { None } else { Some(Gop{samples}) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbsp::{BitWriter, rbsp_to_ebsp};
+    use crate::testing::find_box;
+
+    const IDR_W_RADL: u8 = 19;
+    const TRAIL_N: u8 = 0;
+    const TRAIL_R: u8 = 1;
+
+    fn nal(unit_type: u8, temporal_id: u8, mut w: BitWriter) -> NAL {
+        w.write_rbsp_trailing_bits();
+        let mut data = vec![unit_type << 1, temporal_id + 1];
+        data.extend(rbsp_to_ebsp(&w.into_bytes()));
+        NAL::new(0, Bytes::from(data)).unwrap()
+    }
+
+    // Main profile, progressive frames, level 3.1, with a sub-layer level for the first sub-layers
+    fn profile_tier_level(w: &mut BitWriter, max_sub_layers_minus1: u32) {
+        w.write_bits(2, 0); w.write_bit(false); w.write_bits(5, 1); // general_profile_space, general_tier_flag, general_profile_idc
+        w.write_bits(32, 0x6000_0000); // general_profile_compatibility_flags
+        w.write_bits(32, 0x9000_0000); w.write_bits(16, 0); // progressive_source, frame_only_constraint
+        w.write_bits(8, 93);
+        for _ in 0 .. max_sub_layers_minus1 { w.write_bit(false); w.write_bit(true); }
+        if max_sub_layers_minus1 > 0 { w.write_bits(2 * (8 - max_sub_layers_minus1 as u8), 0); }
+        for _ in 0 .. max_sub_layers_minus1 { w.write_bits(8, 90); }
+    }
+
+    // two temporal sub-layers at 25 frames per second
+    fn vps() -> NAL {
+        let mut w = BitWriter::new();
+        w.write_bits(4, 0); w.write_bit(true); w.write_bit(true); w.write_bits(6, 0); // id, base layer flags, max_layers_minus1
+        w.write_bits(3, 1); w.write_bit(true); w.write_bits(16, 0xFFFF);
+        profile_tier_level(&mut w, 1);
+        w.write_bit(false); w.write_ue(4); w.write_ue(2); w.write_ue(0); // sub-layer ordering info of the highest sub-layer
+        w.write_bits(6, 0); w.write_ue(0); // vps_max_layer_id, vps_num_layer_sets_minus1
+        w.write_bit(true); w.write_bits(32, 1); w.write_bits(32, 25);
+        nal(32, 0, w)
+    }
+
+    // 4:2:0 8 bit 1920x1080, coded as 1920x1088 with a conformance window
+    fn sps(log2_max_pic_order_cnt_lsb_minus4: u32) -> NAL {
+        let mut w = BitWriter::new();
+        w.write_bits(4, 0); w.write_bits(3, 0); w.write_bit(true);
+        profile_tier_level(&mut w, 0);
+        w.write_ue(0); w.write_ue(1); w.write_ue(1920); w.write_ue(1088); // id, chroma_format_idc, picture size
+        w.write_bit(true); w.write_ue(0); w.write_ue(0); w.write_ue(0); w.write_ue(4);
+        w.write_ue(0); w.write_ue(0); w.write_ue(log2_max_pic_order_cnt_lsb_minus4);
+        nal(33, 0, w)
+    }
+
+    fn pps() -> NAL {
+        let mut w = BitWriter::new();
+        w.write_ue(0); w.write_ue(0); w.write_bit(false); w.write_bit(false); w.write_bits(3, 0);
+        nal(34, 0, w)
+    }
+
+    // slice segment of a picture with 16 pic_order_cnt_lsb values, segments after the first end at the PPS id
+    fn slice(unit_type: u8, temporal_id: u8, first_slice_segment_in_pic_flag: bool, pic_order_cnt_lsb: u32) -> NAL {
+        let mut w = BitWriter::new();
+        w.write_bit(first_slice_segment_in_pic_flag);
+        if (16 ..= 23).contains(&unit_type) { w.write_bit(false); }
+        w.write_ue(0);
+        if first_slice_segment_in_pic_flag {
+            w.write_ue(2);
+            if unit_type != IDR_W_RADL { w.write_bits(4, pic_order_cnt_lsb); }
+        }
+        nal(unit_type, temporal_id, w)
+    }
+
+    #[test]
+    fn parameter_sets() {
+        let vps = Vps::parse(&vps().data).unwrap();
+        assert_eq!(vps.vps_max_sub_layers_minus1, 1);
+        assert_eq!(vps.profile_tier_level.general_level_idc, 93);
+        // the timing info is read after the sub-layer part of the profile_tier_level
+        assert_eq!(vps.frame_rate(), Some(h264::FrameRate{timescale: 25, sample_duration: 1}));
+
+        let parsed = Sps::parse(&sps(0).data).unwrap();
+        let ptl = &parsed.profile_tier_level;
+        assert_eq!((ptl.general_profile_space, ptl.general_tier_flag, ptl.general_profile_idc), (0, false, 1));
+        assert_eq!(ptl.general_profile_compatibility_flags, 0x6000_0000);
+        assert_eq!(ptl.general_constraint_indicator_flags, 0x9000_0000_0000);
+        assert_eq!(ptl.general_level_idc, 93);
+        assert_eq!((parsed.width(), parsed.height()), (1920, 1080));
+
+        assert!(Sps::parse(&sps(12).data).is_some());
+        assert!(Sps::parse(&sps(13).data).is_none());
+    }
+
+    #[test]
+    fn picture_order_count() {
+        let mut nals = vec![vps(), sps(0), pps(), slice(IDR_W_RADL, 0, true, 0)];
+        // the lsb wraps after 15, the sub-layer non-reference picture doesn't update prevTid0Pic
+        for (unit_type, lsb) in [(TRAIL_R, 8), (TRAIL_R, 14), (TRAIL_R, 2), (TRAIL_N, 12), (TRAIL_R, 7)] {
+            nals.push(slice(unit_type, 0, true, lsb));
+        }
+        let pocs: Vec<i32> = AccessUnits::new(nals.into_iter()).map(|au| au.poc).collect();
+        assert_eq!(pocs, [0, 8, 14, 18, 12, 23]);
+    }
+
+    #[test]
+    fn slice_segments_of_a_picture() {
+        let nals = vec![
+            vps(), sps(0), pps(), slice(IDR_W_RADL, 0, true, 0), slice(IDR_W_RADL, 0, false, 0),
+            slice(TRAIL_R, 0, true, 1), slice(TRAIL_R, 0, false, 1), slice(TRAIL_R, 0, false, 1),
+            slice(TRAIL_N, 1, true, 2),
+        ];
+        let access_units: Vec<AccessUnit> = AccessUnits::new(nals.into_iter()).collect();
+        let counts: Vec<usize> = access_units.iter().map(|au| au.nals.len()).collect();
+        assert_eq!(counts, [5, 3, 1]);
+        assert_eq!(access_units.iter().map(|au| au.is_irap).collect::<Vec<_>>(), [true, false, false]);
+        assert!(access_units.iter().all(|au| au.parameter_sets.is_some()));
+    }
+
+    #[test]
+    fn parameter_set_change() {
+        // two groups, the second one with a longer pic_order_cnt_lsb
+        let mut data = vec![];
+        for log2_max_pic_order_cnt_lsb_minus4 in [0, 4] {
+            for nal in [vps(), sps(log2_max_pic_order_cnt_lsb_minus4), pps(), slice(IDR_W_RADL, 0, true, 0)] {
+                data.extend([0, 0, 0, 1]);
+                data.extend(&nal.data[..]);
+            }
+        }
+        let gops: Vec<Gop> = GopReader::new(std::io::Cursor::new(data), None).collect();
+        assert_eq!(gops.len(), 2);
+        let sps_data = |gop: &Gop| gop.parameter_sets().unwrap().1.data.clone();
+        assert_ne!(sps_data(&gops[0]), sps_data(&gops[1]));
+        assert_eq!(Sps::parse(&sps_data(&gops[1])).unwrap().log2_max_pic_order_cnt_lsb_minus4, 4);
+    }
+
+    #[test]
+    fn hvcc() {
+        let sets = (vps(), sps(0), pps());
+        let moov_info = crate::moov::MoovInfo{
+            width: 1920, height: 1080,
+            sample_entries: vec![crate::moov::SampleEntry{
+                config: crate::moov::DecoderConfig::Hevc{
+                    vps: sets.0.data.to_vec(), sps: sets.1.data.to_vec(), pps: sets.2.data.to_vec(),
+                    sps_info: Sps::parse(&sets.1.data).unwrap(),
+                },
+                width: 1920, height: 1080,
+            }],
+            horizontal_resolution: 4718592, vertical_resolution: 4718592,
+            creation_time: 0, timescale: 25, nal_length_size: 4, in_band_parameter_sets: false,
+        };
+        let mut buf = bytes::BytesMut::with_capacity(4*1024);
+        crate::moov::write_moov(&mut buf, &moov_info);
+        let hvcc = find_box(&buf, b"hvcC");
+        assert_eq!(&hvcc[.. 23], &[
+            1, 0x01, 0x60, 0, 0, 0, 0x90, 0, 0, 0, 0, 0, 93, // version, profile, compatibility and constraint flags, level
+            0xF0, 0x00, 0xFC, 0xFD, 0xF8, 0xF8, 0, 0, // min_spatial_segmentation_idc, parallelismType, chroma, bit depths, avgFrameRate
+            0x0F, 3, // one temporal layer, temporalIdNested, 4 byte lengths, numOfArrays
+        ]);
+        let mut pos = 23;
+        for nal in [&sets.0, &sets.1, &sets.2] {
+            let length = nal.data.len();
+            assert_eq!(&hvcc[pos .. pos + 5], &[0x80 | (nal.data[0] >> 1), 0, 1, 0, length as u8]);
+            assert_eq!(&hvcc[pos + 5 .. pos + 5 + length], &nal.data[..]);
+            pos += 5 + length;
+        }
+        assert_eq!(pos, hvcc.len());
+    }
+}
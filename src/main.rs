@@ -1,134 +1,116 @@
-use std::io::prelude::*;
-use bytes::{BytesMut, BufMut};
-
+mod analyzer;
 mod moov;
 mod moof;
 mod h264;
+mod h265;
+mod av1;
+#[allow(dead_code, clippy::all)] // the baseline mp4 reader, kept for reference, main doesn't use it
 mod mp4_parser;
-
-fn write_atom(parent: &mut BytesMut, id: &[u8; 4], atom: BytesMut) {
-    parent.put_u32_be(atom.len() as u32 + 8_u32);
-    parent.put_slice(&id[..]);
-    parent.put_slice(atom.as_ref());
-}
-
-fn write_ftyp(parent: &mut BytesMut, ) {
-    let mut buf = BytesMut::with_capacity(1024);
-    buf.put(&b"isom"[..]);      // major_brand
-    buf.put_u32_be(0x00000200_u32);          // minor_version
-    buf.put(&b"isom"[..]);
-    buf.put(&b"iso2"[..]);
-    buf.put(&b"avc1"[..]);
-    buf.put(&b"iso6"[..]);
-    buf.put(&b"mp41"[..]);
-
-    write_atom(parent, b"ftyp", buf);
-}
-
-fn write_mdat(parent: &mut BytesMut, data: Vec<u8>) {
-    let mut buf = BytesMut::with_capacity(1024*1024);
-    buf.put_slice(data.as_slice());
-    write_atom(parent, b"mdat", buf);
+mod mux;
+mod rbsp;
+mod timestamps;
+#[cfg(test)]
+mod testing;
+
+// bad command line arguments end the program
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
 }
 
-
-fn write_samples(parent: &mut BytesMut, samples: & Vec<(h264::NalUnitType, Vec<u8>)>) -> (Vec<u32>, bytes::BytesMut) {
-
-    if samples.len() < 4 { panic!("samples count is too small"); }
-
-    let mut samples_sizes = vec![0u32];
-
-    let mut all_size = 0;
-    for (unit_type, sample) in samples {
-        if unit_type != &h264::NalUnitType::CodedSliceNonIdr {
-            samples_sizes[0] += sample.len() as u32 + 4;
-        } else {
-            samples_sizes.push(sample.len() as u32 + 4);
+fn main() {
+    let mut input = String::from("stream_chn0.h264");
+    let mut output = String::from("rust.mp4");
+    let mut options = mux::MuxOptions::default();
+    let mut codec = None;
+    let mut analyze = false;
+    let mut json = false;
+
+    let mut positional = 0;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fps" => {
+                let value = args.next().unwrap_or_else(|| fail("--fps needs a value, e.g. 25 or 30000/1001"));
+                options.frame_rate = Some(h264::FrameRate::parse(&value).unwrap_or_else(|| fail(&format!("can't parse --fps value {}", value))));
+            }
+            "--timestamps" => {
+                let value = args.next().unwrap_or_else(|| fail("--timestamps needs a timestamp format v2 file"));
+                options.timestamps = Some(timestamps::read_v2(&value).unwrap_or_else(|_| std::process::exit(1)));
+            }
+            "--input-format" => {
+                let value = args.next().unwrap_or_else(|| fail("--input-format needs a value: annexb, avcc1, avcc2 or avcc4"));
+                options.input_format = Some(match value.as_str() {
+                    "annexb" => h264::StreamFormat::AnnexB,
+                    "avcc1" => h264::StreamFormat::Avcc(1),
+                    "avcc2" => h264::StreamFormat::Avcc(2),
+                    "avcc4" => h264::StreamFormat::Avcc(4),
+                    _ => fail(&format!("unknown --input-format {}", value)),
+                });
+            }
+            "--nal-length-size" => {
+                let value = args.next().unwrap_or_else(|| fail("--nal-length-size needs a value: 1, 2 or 4"));
+                options.nal_length_size = match value.as_str() {
+                    "1" => 1, "2" => 2, "4" => 4,
+                    _ => fail("--nal-length-size must be 1, 2 or 4"),
+                };
+            }
+            "--on-parameter-change" => {
+                let value = args.next().unwrap_or_else(|| fail("--on-parameter-change needs a value: stsd, init or file"));
+                options.on_parameter_set_change = match value.as_str() {
+                    "stsd" => mux::ParameterSetChange::SampleDescription,
+                    "init" => mux::ParameterSetChange::InitSegment,
+                    "file" => mux::ParameterSetChange::NewFile,
+                    _ => fail(&format!("unknown --on-parameter-change {}", value)),
+                };
+            }
+            "analyze" if positional == 0 && !analyze => analyze = true,
+            "--json" => json = true,
+            "--gops-per-fragment" => {
+                let value = args.next().unwrap_or_else(|| fail("--gops-per-fragment needs a number"));
+                options.gops_per_fragment = value.parse().ok().filter(|n| *n > 0).unwrap_or_else(|| fail("--gops-per-fragment must be a positive number"));
+            }
+            "--sidx" => {
+                let value = args.next().unwrap_or_else(|| fail("--sidx needs a value: ondemand or live"));
+                options.sidx = match value.as_str() {
+                    "ondemand" => mux::SidxMode::OnDemand,
+                    "live" => mux::SidxMode::Live,
+                    _ => fail(&format!("unknown --sidx {}", value)),
+                };
+            }
+            "--default-base-is-moof" => options.default_base_is_moof = true,
+            "--resilient" => options.resilient = true,
+            "--sdtp" => options.sdtp = true,
+            "--avc3" | "--hev1" => options.sample_entry = mux::SampleEntryMode::Avc3,
+            "--codec" => {
+                let value = args.next().unwrap_or_else(|| fail("--codec needs a value: h264, h265 or av1"));
+                codec = Some(match value.as_str() {
+                    "h264" => mux::Codec::H264,
+                    "h265" => mux::Codec::H265,
+                    "av1" => mux::Codec::AV1,
+                    _ => fail(&format!("unknown --codec {}", value)),
+                });
+            }
+            _ => {
+                if positional == 0 { input = arg; } else { output = arg; }
+                positional += 1;
+            }
         }
-        all_size += sample.len() + 4;
     }
-    println!("   samples: {}    all_size: {}     samples_sizes {:?}", samples.len(), all_size, samples_sizes);
 
-    let mut mdat_buf = BytesMut::with_capacity(all_size);
-    for (unit_type, sample) in samples {
-        mdat_buf.put_u32_be(sample.len() as u32);  // 4 sample_count
-        mdat_buf.put_slice(sample.as_slice());
-        // sample.len() + 4;
+    if analyze {
+        if analyzer::analyze(&input, options.input_format, options.frame_rate, json).is_err() { std::process::exit(1); }
+        return;
     }
-    (samples_sizes, mdat_buf)
-//
-////
-////
-////        let samples_sizes = vec![23386,40,382,82,54,62,49,74,102,108,110,101,95,100,165,303,522,1074,5915,20497,41852,77201,74790,64662,53197,52811,41780,26423,17048,15035];
-//    moof::write_moof(&mut buf, 1, samples_sizes);
-//
-//    write_atom(parent, b"ftyp", buf);
-}
-
-//fn main_mp4() {
-//    let mut buf = BytesMut::with_capacity(10*1024*1024);
-//    write_ftyp(&mut buf);
-//    moov::write_moov(&mut buf);
-//
-//    let samples_sizes = vec![23386,40,382,82,54,62,49,74,102,108,110,101,95,100,165,303,522,1074,5915,20497,41852,77201,74790,64662,53197,52811,41780,26423,17048,15035];
-//    moof::write_moof(&mut buf, 1, samples_sizes);
-//
-//    let mut file = std::fs::File::create("rust.mp4").unwrap();
-//    file.write(buf.as_ref()).unwrap();
-//}
 
-fn main() {
-    let mut buf = BytesMut::with_capacity(50*1024*1024);
-    write_ftyp(&mut buf);
-
-    // main_mp4();
     // mp4_parser::main_mp4_parser();
-    let (idrs, sps, pps) = h264::main_h264("stream_chn0.h264").unwrap();
-
-    let moov_info = moov::MoovInfo{
-        sps: sps.data, pps: pps.data,
-        width: 1920, height: 1080,
-        horizontal_resolution: 4718592, vertical_resolution: 4718592,
-        creation_time: 0, timescale: 999999
+    let result = match codec.unwrap_or_else(|| mux::Codec::from_path(&input)) {
+        mux::Codec::H264 => mux::mux_h264(&input, &output, &options),
+        mux::Codec::H265 => mux::mux_h265(&input, &output, &options),
+        mux::Codec::AV1 => mux::mux_av1(&input, &output, &options),
     };
-    moov::write_moov(&mut buf, &moov_info);
-
-    println!("mdats: {} ", idrs.len());
-
-    let mut seq = 0u32;
-    for mdat in &idrs {
-        let samples = &mdat.samples;
-        let (samples_sizes, mdat_buf) = write_samples(&mut buf, samples);
-
-        // println!("samples_sizes: {} {} {} ", samples_sizes[0], samples_sizes[1], samples_sizes[2]);
-
-        let base_data_offset = buf.len() as u64;
-        let default_sample_duration = moov_info.timescale / 30u32;
-        let base_media_decode_time = default_sample_duration as u64 * samples_sizes.len() as u64 * seq as u64;
-
-        // let sample_duration : [u32; 30] = [33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334,33333,33333,33334];
-
-        let mut samples_info = vec![];
-        for i in 0 .. samples_sizes.len() {
-            let sample_info = moof::SampleInfo {
-                size: samples_sizes[i],
-                duration: 0,
-                flags: 0,
-            };
-            samples_info.push(sample_info);
-        }
-
-        moof::write_moof(&mut buf, seq+1, base_data_offset, base_media_decode_time, default_sample_duration, samples_info);
-        write_mdat(&mut buf, mdat_buf.to_vec());
-        seq += 1;
+    if let Err(e) = result {
+        eprintln!("Can't mux {} into {}: {}", input, output, e);
+        std::process::exit(1);
     }
-
-    let mut file = std::fs::File::create("rust.mp4").unwrap();
-    file.write(buf.as_ref()).unwrap();
-
-//    let first_sample_flags = moof::SampleFlags::parse(33554432);
-//    let default_sample_flags = moof::SampleFlags::parse(16842752);
-//
-//    println!("first_sample_flags: {:#?} ", first_sample_flags);
-//    println!("default_sample_flags: {:#?} ", default_sample_flags);
 }
@@ -4,12 +4,13 @@ pub struct SampleInfo{
     pub duration: u32,
     pub size: u32,
     pub flags: u32,
+    pub composition_time_offset: i32,
 }
 
-#[derive(Clone, PartialEq, Debug)] pub enum SampleLeading { UNKNOWN=0, LEADINGDEP=1, NOTLEADING=2, LEADINGNODEP=3 }
-#[derive(Clone, PartialEq, Debug)] pub enum SampleDepends { UNKNOWN=0, DEPENDS=1, NOTDEPENDS=2, RESERVED=3 }
-#[derive(Clone, PartialEq, Debug)] pub enum SampleDepended { UNKNOWN=0, NOTDISPOSABLE=1, DISPOSABLE=2, RESERVED=3 }
-#[derive(Clone, PartialEq, Debug)] pub enum SampleRedundancy { UNKNOWN=0, REDUNDANT=1, NOTREDUNDANT=2, RESERVED=3 }
+#[derive(Clone, PartialEq, Debug)] #[allow(clippy::upper_case_acronyms)] pub enum SampleLeading { UNKNOWN=0, LEADINGDEP=1, NOTLEADING=2, LEADINGNODEP=3 }
+#[derive(Clone, PartialEq, Debug)] #[allow(clippy::upper_case_acronyms)] pub enum SampleDepends { UNKNOWN=0, DEPENDS=1, NOTDEPENDS=2, RESERVED=3 }
+#[derive(Clone, PartialEq, Debug)] #[allow(clippy::upper_case_acronyms)] pub enum SampleDepended { UNKNOWN=0, NOTDISPOSABLE=1, DISPOSABLE=2, RESERVED=3 }
+#[derive(Clone, PartialEq, Debug)] #[allow(clippy::upper_case_acronyms)] pub enum SampleRedundancy { UNKNOWN=0, REDUNDANT=1, NOTREDUNDANT=2, RESERVED=3 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct SampleFlags {
@@ -21,6 +22,18 @@ pub struct SampleFlags {
     degradation_priority: u16,
 }
 impl SampleFlags {
+    // flags of a sample derived from the bitstream: whether it is a sync sample,
+    // whether it references other pictures and whether other pictures reference it
+    pub fn new(is_sync: bool, depends_on_others: bool, is_disposable: bool) -> SampleFlags {
+        SampleFlags {
+            is_leading: SampleLeading::UNKNOWN,
+            depends_on: if depends_on_others { SampleDepends::DEPENDS } else { SampleDepends::NOTDEPENDS },
+            is_depended_on: if is_disposable { SampleDepended::DISPOSABLE } else { SampleDepended::NOTDISPOSABLE },
+            has_redundancy: SampleRedundancy::UNKNOWN,
+            is_non_sync_sample: !is_sync,
+            degradation_priority: 0,
+        }
+    }
     pub fn parse(flags: u32) -> SampleFlags {
         //bit(4) reserved=0;
         //unsigned int(2) is_leading;
@@ -35,66 +48,139 @@ impl SampleFlags {
         let sample_is_depended_on : u8          = ((flags & 0b0000_0000_1100_0000_0000_0000_0000_0000_u32) >> 22) as u8;
         let sample_has_redundancy : u8          = ((flags & 0b0000_0000_0011_0000_0000_0000_0000_0000_u32) >> 20) as u8;
         let sample_is_non_sync_sample : bool    = ((flags & 0b0000_0000_0000_0001_0000_0000_0000_0000_u32) >> 16) == 1;
-        let sample_degradation_priority : u16   = ((flags & 0b0000_0000_0000_0000_1111_1111_1111_1111_u32) >> 0) as u16;
-
-        let mut flags = SampleFlags{
-            is_leading: SampleLeading::UNKNOWN,
-            depends_on: SampleDepends::UNKNOWN,
-            is_depended_on: SampleDepended::UNKNOWN,
-            has_redundancy: SampleRedundancy::UNKNOWN,
-            is_non_sync_sample: false,
-            degradation_priority: 0,
-        };
-        if is_leading == SampleLeading::UNKNOWN as u8 { flags.is_leading = SampleLeading::UNKNOWN; }
-        else if is_leading == SampleLeading::LEADINGDEP as u8 { flags.is_leading = SampleLeading::LEADINGDEP; }
-        else if is_leading == SampleLeading::NOTLEADING as u8 { flags.is_leading = SampleLeading::NOTLEADING; }
-        else if is_leading == SampleLeading::LEADINGNODEP as u8 { flags.is_leading = SampleLeading::LEADINGNODEP; }
-        else { unimplemented!(); }
-
-        if sample_depends_on == SampleDepends::UNKNOWN as u8 { flags.depends_on = SampleDepends::UNKNOWN; }
-        else if sample_depends_on == SampleDepends::DEPENDS as u8 { flags.depends_on = SampleDepends::DEPENDS; }
-        else if sample_depends_on == SampleDepends::NOTDEPENDS as u8 { flags.depends_on = SampleDepends::NOTDEPENDS; }
-        else { unimplemented!(); }
-
-        if sample_is_depended_on == SampleDepended::UNKNOWN as u8 { flags.is_depended_on = SampleDepended::UNKNOWN; }
-        else if sample_is_depended_on == SampleDepended::NOTDISPOSABLE as u8 { flags.is_depended_on = SampleDepended::NOTDISPOSABLE; }
-        else if sample_is_depended_on == SampleDepended::DISPOSABLE as u8 { flags.is_depended_on = SampleDepended::DISPOSABLE; }
-        else { unimplemented!(); }
-
-        if sample_has_redundancy == SampleRedundancy::UNKNOWN as u8 { flags.has_redundancy = SampleRedundancy::UNKNOWN; }
-        else if sample_has_redundancy == SampleRedundancy::REDUNDANT as u8 { flags.has_redundancy = SampleRedundancy::REDUNDANT; }
-        else if sample_has_redundancy == SampleRedundancy::NOTREDUNDANT as u8 { flags.has_redundancy = SampleRedundancy::NOTREDUNDANT; }
-        else { unimplemented!(); }
-
-        flags.is_non_sync_sample = sample_is_non_sync_sample;
-        flags.degradation_priority = sample_degradation_priority;
-
-        flags
+        let sample_degradation_priority : u16   = (flags & 0b0000_0000_0000_0000_1111_1111_1111_1111_u32) as u16;
+
+        SampleFlags {
+            is_leading: match is_leading {
+                0 => SampleLeading::UNKNOWN,
+                1 => SampleLeading::LEADINGDEP,
+                2 => SampleLeading::NOTLEADING,
+                _ => SampleLeading::LEADINGNODEP,
+            },
+            depends_on: match sample_depends_on {
+                0 => SampleDepends::UNKNOWN,
+                1 => SampleDepends::DEPENDS,
+                2 => SampleDepends::NOTDEPENDS,
+                _ => SampleDepends::RESERVED,
+            },
+            is_depended_on: match sample_is_depended_on {
+                0 => SampleDepended::UNKNOWN,
+                1 => SampleDepended::NOTDISPOSABLE,
+                2 => SampleDepended::DISPOSABLE,
+                _ => SampleDepended::RESERVED,
+            },
+            has_redundancy: match sample_has_redundancy {
+                0 => SampleRedundancy::UNKNOWN,
+                1 => SampleRedundancy::REDUNDANT,
+                2 => SampleRedundancy::NOTREDUNDANT,
+                _ => SampleRedundancy::RESERVED,
+            },
+            is_non_sync_sample: sample_is_non_sync_sample,
+            degradation_priority: sample_degradation_priority,
+        }
     }
     pub fn serialize(&self) -> u32 {
-        let mut f = 0_u32;
-        f = f | self.is_leading.clone() as u32;
-        f = f << 2; f = f | self.depends_on.clone() as u32;
-        f = f << 2; f = f | self.is_depended_on.clone() as u32;
-        f = f << 2; f = f | self.has_redundancy.clone() as u32;
-        f = f << 3;
-        f = f << 1; f = f | self.is_non_sync_sample as u32;
-        f = f << 16; f = f | self.degradation_priority as u32;
+        let mut f = self.is_leading.clone() as u32;
+        f <<= 2; f |= self.depends_on.clone() as u32;
+        f <<= 2; f |= self.is_depended_on.clone() as u32;
+        f <<= 2; f |= self.has_redundancy.clone() as u32;
+        f <<= 3;
+        f <<= 1; f |= self.is_non_sync_sample as u32;
+        f <<= 16; f |= self.degradation_priority as u32;
         f
     }
+    // 8.6.4 one byte of the sdtp box: is_leading, sample_depends_on, sample_is_depended_on, sample_has_redundancy
+    pub fn sdtp_entry(flags: u32) -> u8 { ((flags >> 20) & 0xFF) as u8 }
 }
 
 fn write_atom(parent: &mut BytesMut, id: &[u8; 4], atom: BytesMut) {
+    parent.reserve(atom.len() + 8); // a fragment of several groups outgrows the initial capacity
     parent.put_u32_be(atom.len() as u32 + 8_u32);
     parent.put_slice(&id[..]);
     parent.put_slice(atom.as_ref());
 }
 
-pub fn write_moof(parent: &mut BytesMut, sequence_number: u32, base_data_offset: u64, base_media_decode_time: u64, default_sample_duration: u32, samples_info: Vec<SampleInfo>) {
-    let mut buf = BytesMut::with_capacity(2*1024);
-    write_mfhd(&mut buf, sequence_number);
-    let data_offset = buf.len();
-    write_traf(&mut buf, default_sample_duration, samples_info, base_data_offset, base_media_decode_time, data_offset);
+// One reference of a sidx box: a fragment, its moof and mdat
+#[derive(Debug, Clone)]
+pub struct SegmentReference {
+    pub size: u32,                          // referenced_size, bytes of the moof and mdat
+    pub earliest_presentation_time: u64,
+    pub duration: u32,                      // subsegment_duration
+    pub starts_with_sap: bool,
+    pub sap_type: u8,                       // 1 or 2 for a sync sample, 0 when the fragment has none
+    pub sap_delta_time: u32,                // presentation time of the first SAP after the earliest one
+}
+
+impl SegmentReference {
+    // from the samples of a fragment written with write_moof and the size of its moof and mdat
+    pub fn new(samples_info: &[SampleInfo], base_media_decode_time: u64, size: u32) -> SegmentReference {
+        let mut dts = base_media_decode_time;
+        let mut pts = vec![];
+        for sample_info in samples_info {
+            pts.push((dts as i64 + sample_info.composition_time_offset as i64).max(0) as u64);
+            dts += sample_info.duration as u64;
+        }
+        let earliest_presentation_time = pts.iter().copied().min().unwrap_or(base_media_decode_time);
+        let is_sync = |sample_info: &SampleInfo| !SampleFlags::parse(sample_info.flags).is_non_sync_sample;
+
+        let mut reference = SegmentReference{
+            size, earliest_presentation_time,
+            duration: (dts - base_media_decode_time) as u32,
+            starts_with_sap: samples_info.first().is_some_and(is_sync),
+            sap_type: 0, sap_delta_time: 0,
+        };
+        // 8.16.3 SAP type 1 when the sync sample is also presented first of the samples from it on, type 2 otherwise
+        if let Some(sap) = samples_info.iter().position(is_sync) {
+            let presented_first = pts[sap ..].iter().all(|t| *t >= pts[sap]);
+            reference.sap_type = if presented_first { 1 } else { 2 };
+            reference.sap_delta_time = (pts[sap] - earliest_presentation_time) as u32;
+        }
+        reference
+    }
+}
+
+// referenced_size has 31 bits, a larger fragment can't be indexed
+pub const MAX_REFERENCED_SIZE: usize = 0x7FFF_FFFF;
+
+// 8.16.3 Segment index box for the references in order, first_offset is the distance from the end of
+// the sidx to the first of them
+pub fn write_sidx(parent: &mut BytesMut, timescale: u32, first_offset: u64, references: &[SegmentReference]) {
+    let mut buf = BytesMut::with_capacity(sidx_size(references.len()));
+    buf.put_u8(1);  // 1 version
+    buf.put_u8(0); buf.put_u8(0); buf.put_u8(0);  // 3 flags
+    buf.put_u32_be(1);  // 4 reference_ID, the track_ID
+    buf.put_u32_be(timescale);  // 4 timescale
+    buf.put_u64_be(references.first().map_or(0, |r| r.earliest_presentation_time));  // 8 earliest_presentation_time
+    buf.put_u64_be(first_offset);  // 8 first_offset
+    buf.put_u16_be(0);  // 2 reserved
+    buf.put_u16_be(references.len() as u16);  // 2 reference_count
+    for reference in references {
+        buf.put_u32_be(reference.size);  // 4 reference_type 0 (media), referenced_size up to MAX_REFERENCED_SIZE
+        buf.put_u32_be(reference.duration);  // 4 subsegment_duration
+        let sap = (reference.starts_with_sap as u32) << 31 | (reference.sap_type as u32 & 0x7) << 28 | (reference.sap_delta_time & 0x0FFF_FFFF);
+        buf.put_u32_be(sap);  // 4 starts_with_SAP, SAP_type, SAP_delta_time
+    }
+
+    write_atom(parent, b"sidx", buf);
+}
+
+// size of a version 1 sidx box with the given number of references
+pub fn sidx_size(reference_count: usize) -> usize { 8 + 4 + 4 + 4 + 8 + 8 + 2 + 2 + 12 * reference_count }
+
+#[allow(clippy::too_many_arguments)]
+// base_data_offset is the file position of the moof, None sets default-base-is-moof instead: the fragment
+// doesn't depend on its position and can be served, cached or concatenated on its own
+pub fn write_moof(parent: &mut BytesMut, sequence_number: u32, sample_description_index: Option<u32>, base_data_offset: Option<u64>, base_media_decode_time: u64, default_sample_duration: u32, samples_info: &[SampleInfo], sdtp: bool) {
+    let moof = |data_offset: i32| {
+        let mut buf = BytesMut::with_capacity(2*1024);
+        write_mfhd(&mut buf, sequence_number);
+        write_traf(&mut buf, sample_description_index, default_sample_duration, samples_info, base_data_offset, base_media_decode_time, data_offset, sdtp);
+        buf
+    };
+    // both bases are the first byte of the moof, the samples follow it and the mdat header.
+    // The data_offset value doesn't change the size of the moof, a first pass measures it.
+    let size = moof(0).len() + 8;
+    let buf = moof((size + 8) as i32);
     // println!("moof size {}: ", buf.len());
 
     write_atom(parent, b"moof", buf);
@@ -109,49 +195,69 @@ pub fn write_mfhd(parent: &mut BytesMut, sequence_number: u32) {
     write_atom(parent, b"mfhd", buf);
 }
 
-pub fn write_traf(parent: &mut BytesMut, default_sample_duration: u32, samples_info: Vec<SampleInfo>, base_data_offset: u64, base_media_decode_time: u64, data_offset: usize) {
+#[allow(clippy::too_many_arguments)]
+pub fn write_traf(parent: &mut BytesMut, sample_description_index: Option<u32>, default_sample_duration: u32, samples_info: &[SampleInfo], base_data_offset: Option<u64>, base_media_decode_time: u64, data_offset: i32, sdtp: bool) {
     let mut buf = BytesMut::with_capacity(1024);
 //    buf.put_u32_be(30);  // 4 sample_number
 //    buf.put_u32_be(29);  // 4 first_sample_index
-    write_tfhd(&mut buf,base_data_offset, default_sample_duration,samples_info[0].size);
+    let default_sample_flags = default_sample_flags(samples_info);
+    let default_sample_size = samples_info.first().map_or(0, |sample_info| sample_info.size);
+    write_tfhd(&mut buf, base_data_offset, sample_description_index, default_sample_duration, default_sample_size, default_sample_flags);
     write_tfdt(&mut buf, base_media_decode_time);
-    let data_offset = data_offset + buf.len();
-    write_trun(&mut buf, samples_info, data_offset);
+    if sdtp { write_sdtp(&mut buf, samples_info); }
+    write_trun(&mut buf, samples_info, data_offset, default_sample_duration, default_sample_flags);
 
     write_atom(parent, b"traf", buf);
 }
 
-pub fn write_tfhd(parent: &mut BytesMut, base_data_offset: u64, default_sample_duration: u32, default_sample_size: u32) {
+// The flags most samples after the first one share. The first sample, usually a sync sample, can differ
+// through first_sample_flags, the others need per-sample flags in trun when they differ from the default.
+// 0 for a fragment without samples.
+fn default_sample_flags(samples_info: &[SampleInfo]) -> u32 {
+    let rest = if samples_info.len() > 1 { &samples_info[1..] } else { samples_info };
+    let mut counts: Vec<(u32, usize)> = vec![];
+    for sample_info in rest {
+        match counts.iter_mut().find(|(flags, _)| *flags == sample_info.flags) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((sample_info.flags, 1)),
+        }
+    }
+    let mut best = (0, 0);
+    for (flags, count) in counts { if count > best.1 { best = (flags, count); } }
+    best.0
+}
+
+pub fn write_tfhd(parent: &mut BytesMut, base_data_offset: Option<u64>, sample_description_index: Option<u32>, default_sample_duration: u32, default_sample_size: u32, default_sample_flags: u32) {
     let mut buf = BytesMut::with_capacity(1024*1024);
     buf.put_u8(0);  // 1 byte version
 
     let mut flags = 0x0_u32;
-    let base_data_offset_present = true;
-    let sample_description_index_present = false;
+    let base_data_offset_present = base_data_offset.is_some();
+    let sample_description_index_present = sample_description_index.is_some();
     let default_sample_duration_present = true;
     let default_sample_size_present = true;
     let default_sample_flags_present = true;
     let duration_is_empty = false;
-    let default_base_is_moof = false;
-
-    if base_data_offset_present         { flags = flags | 0x000001; } // base-data-offset-present
-    if sample_description_index_present { flags = flags | 0x000002; } // sample-description-index-present
-    if default_sample_duration_present  { flags = flags | 0x000008; } // default-sample-duration-present
-    if default_sample_size_present      { flags = flags | 0x000010; } // default-sample-size-present
-    if default_sample_flags_present     { flags = flags | 0x000020; } // default-sample-flags-present
-    if duration_is_empty                { flags = flags | 0x010000; } // duration-is-empty
-    if default_base_is_moof             { flags = flags | 0x020000; } // default-base-is-moof
+    let default_base_is_moof = base_data_offset.is_none();
+
+    if base_data_offset_present         { flags |= 0x000001; } // base-data-offset-present
+    if sample_description_index_present { flags |= 0x000002; } // sample-description-index-present
+    if default_sample_duration_present  { flags |= 0x000008; } // default-sample-duration-present
+    if default_sample_size_present      { flags |= 0x000010; } // default-sample-size-present
+    if default_sample_flags_present     { flags |= 0x000020; } // default-sample-flags-present
+    if duration_is_empty                { flags |= 0x010000; } // duration-is-empty
+    if default_base_is_moof             { flags |= 0x020000; } // default-base-is-moof
     // buf.put_u8(0); buf.put_u8(0); buf.put_u8(0x39);  // 3 flags
     // println!("tfhd flags: 0x{:06x}        0x{:02x}: 0x{:02x}: 0x{:02x}", flags, (flags >> 16) as u8, (flags >> 8) as u8, (flags >> 0) as u8);
-    buf.put_u8((flags >> 16) as u8); buf.put_u8((flags >> 8) as u8); buf.put_u8((flags >> 0) as u8); // 3 flags
+    buf.put_u8((flags >> 16) as u8); buf.put_u8((flags >> 8) as u8); buf.put_u8(flags as u8); // 3 flags
 
 
     buf.put_u32_be(1); // 4 track_ID
-    if base_data_offset_present { buf.put_u64_be(base_data_offset); }
-    // if sample_description_index_present { buf.put_u32_be(0); } // 4 default_sample_description_index
+    if let Some(offset) = base_data_offset { buf.put_u64_be(offset); }
+    if let Some(index) = sample_description_index { buf.put_u32_be(index); } // 4 sample_description_index
     if default_sample_duration_present { buf.put_u32_be(default_sample_duration); }
     if default_sample_size_present { buf.put_u32_be(default_sample_size); }
-    if default_sample_flags_present { buf.put_u32_be(16842752); }
+    if default_sample_flags_present { buf.put_u32_be(default_sample_flags); }
 
     write_atom(parent, b"tfhd", buf);
 }
@@ -163,62 +269,190 @@ pub fn write_tfdt(parent: &mut BytesMut, base_media_decode_time: u64) {
 
     write_atom(parent, b"tfdt", buf);
 }
-pub fn write_trun(parent: &mut BytesMut, samples_info: Vec<SampleInfo>, data_offset: usize) {
+pub fn write_sdtp(parent: &mut BytesMut, samples_info: &[SampleInfo]) {
+    let mut buf = BytesMut::with_capacity(4 + samples_info.len());
+    buf.put_u8(0);  // 1 version
+    buf.put_u8(0); buf.put_u8(0); buf.put_u8(0);  // 3 flags
+    for sample_info in samples_info {
+        buf.put_u8(SampleFlags::sdtp_entry(sample_info.flags)); // 1 is_leading, sample_depends_on, sample_is_depended_on, sample_has_redundancy
+    }
+
+    write_atom(parent, b"sdtp", buf);
+}
+pub fn write_trun(parent: &mut BytesMut, samples_info: &[SampleInfo], data_offset: i32, default_sample_duration: u32, default_sample_flags: u32) {
     let mut buf = BytesMut::with_capacity(1024*1024);
-    let version = 0u8;
-    buf.put_u8(version);  // 1 version
 
+    // first_sample_flags is enough when only the first sample differs from the tfhd default,
+    // otherwise every sample carries its own flags
+    let rest_are_default = samples_info.iter().skip(1).all(|s| s.flags == default_sample_flags);
     let data_offset_present = true;
-    let first_sample_flags_present = true;
-    let sample_duration_present = false;
+    let first_sample_flags_present = rest_are_default && samples_info.first().is_some_and(|first| first.flags != default_sample_flags);
+    let sample_duration_present = samples_info.iter().any(|s| s.duration != default_sample_duration);
     let sample_size_present = true;
-    let sample_flags_present = false;
-    let sample_composition_time_offsets_present = false;
+    let sample_flags_present = !rest_are_default;
+    let sample_composition_time_offsets_present = samples_info.iter().any(|s| s.composition_time_offset != 0);
+
+    let version = if sample_composition_time_offsets_present { 1u8 } else { 0u8 }; // version 1 has signed offsets
+    buf.put_u8(version);  // 1 version
 
     {
         let mut flags = 0x0_u32;
-        if data_offset_present        { flags = flags | 0x000001; } // 0x000001 data-offset-present.
-        if first_sample_flags_present { flags = flags | 0x000004; } // 0x000004 first-sample-flags-present
-        if sample_duration_present    { flags = flags | 0x000100; } // 0x000100 sample-duration-present
-        if sample_size_present        { flags = flags | 0x000200; } // 0x000200 sample-size-present
-        if sample_flags_present       { flags = flags | 0x000400; } // 0x000400 sample-flags-present
-        // if sample_composition_time_offsets_present { flags = flags | 0x000800; } // 0x000800 sample-composition-time-offsets-present
+        if data_offset_present        { flags |= 0x000001; } // 0x000001 data-offset-present.
+        if first_sample_flags_present { flags |= 0x000004; } // 0x000004 first-sample-flags-present
+        if sample_duration_present    { flags |= 0x000100; } // 0x000100 sample-duration-present
+        if sample_size_present        { flags |= 0x000200; } // 0x000200 sample-size-present
+        if sample_flags_present       { flags |= 0x000400; } // 0x000400 sample-flags-present
+        if sample_composition_time_offsets_present { flags |= 0x000800; } // 0x000800 sample-composition-time-offsets-present
 
         // println!("trup flags: 0x{:06x}        0x{:02x}: 0x{:02x}: 0x{:02x}", flags, (flags >> 16) as u8, (flags >> 8) as u8, (flags >> 0) as u8);
-        buf.put_u8((flags >> 16) as u8); buf.put_u8((flags >> 8) as u8); buf.put_u8((flags >> 0) as u8); // 3 flags
+        buf.put_u8((flags >> 16) as u8); buf.put_u8((flags >> 8) as u8); buf.put_u8(flags as u8); // 3 flags
 
     }
 
     let sample_count = samples_info.len() as u32;
     buf.put_u32_be(sample_count);  // 4 sample_count
 
-    {
-        let mut data_offset = data_offset + buf.len();
-        if data_offset_present          { data_offset += 4; }
-        if first_sample_flags_present   { data_offset += 4; }
-        if sample_duration_present      { data_offset += sample_count as usize * 4; }
-        if sample_size_present          { data_offset += sample_count as usize * 4; }
-        if sample_flags_present         { data_offset += sample_count as usize * 4; }
-        data_offset += 4+4; // trun atom size + id
-        data_offset += 4+4; // traf atom size + id
-        data_offset += 4+4; // moof atom size + id
-        data_offset += 4+4; // mdat atom size + id
-        // println!("trun data_offset size {}: ", data_offset);
-        if data_offset_present { buf.put_i32_be(data_offset as i32); } // 4 data_offset
-    }
-
-    if first_sample_flags_present { buf.put_u32_be(33554432); } // 4 first_sample_flags
+    if data_offset_present { buf.put_i32_be(data_offset); } // 4 data_offset
+
+    if first_sample_flags_present { buf.put_u32_be(samples_info[0].flags); } // 4 first_sample_flags
 
     for sample_info in samples_info {
         if sample_duration_present { buf.put_u32_be(sample_info.duration); } // 4 sample_duration
         if sample_size_present     { buf.put_u32_be(sample_info.size); }    // 4 sample_size
-        // if sample_flags_present    { buf.put_u32_be(sample_flags[s] as u32); }    // 4 sample_flags
-        // if sample_composition_time_offsets_present {
-        //     if version == 0 { buf.put_u32_be( sample_composition_time_offsets[s] as u32); }
-        //     else { buf.put_i32_be( sample_composition_time_offsets[s] as i32); }
-        // }
+        if sample_flags_present    { buf.put_u32_be(sample_info.flags); }    // 4 sample_flags
+        if sample_composition_time_offsets_present {
+            buf.put_i32_be(sample_info.composition_time_offset); // 4 sample_composition_time_offset
+        }
     }
 
     write_atom(parent, b"trun", buf);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{be32, find_box};
+
+    fn sample(is_sync: bool) -> SampleInfo {
+        SampleInfo{duration: 3000, size: 100, flags: SampleFlags::new(is_sync, !is_sync, false).serialize(), composition_time_offset: 0}
+    }
+
+    #[test]
+    fn reserved_sample_flags() {
+        // every 2 bit field at 3, the reserved value of all but is_leading
+        let flags = SampleFlags::parse(0x0FF1_FFFF);
+        assert_eq!(flags.is_leading, SampleLeading::LEADINGNODEP);
+        assert_eq!(flags.depends_on, SampleDepends::RESERVED);
+        assert_eq!(flags.is_depended_on, SampleDepended::RESERVED);
+        assert_eq!(flags.has_redundancy, SampleRedundancy::RESERVED);
+        assert_eq!(flags.serialize(), 0x0FF1_FFFF);
+    }
+
+    #[test]
+    fn sample_flags_in_trun() {
+        let sync = sample(true).flags;
+        let non_sync = sample(false).flags;
+
+        // one sync sample first: the default flags and first_sample_flags
+        let samples = [sample(true), sample(false), sample(false)];
+        assert_eq!(default_sample_flags(&samples), non_sync);
+        let mut buf = BytesMut::new();
+        write_trun(&mut buf, &samples, 0, 3000, non_sync);
+        let trun = find_box(&buf, b"trun");
+        assert_eq!(be32(trun, 0) & 0xFFFFFF, 0x000205); // data-offset, first-sample-flags, sample-size
+        assert_eq!(be32(trun, 12), sync);
+
+        // sync samples after the first one: flags for every sample
+        let samples = [sample(true), sample(false), sample(true), sample(false)];
+        assert_eq!(default_sample_flags(&samples), non_sync);
+        let mut buf = BytesMut::new();
+        write_trun(&mut buf, &samples, 0, 3000, non_sync);
+        let trun = find_box(&buf, b"trun");
+        assert_eq!(be32(trun, 0) & 0xFFFFFF, 0x000601); // data-offset, sample-size, sample-flags
+        let flags: Vec<u32> = (0 .. 4).map(|i| be32(trun, 12 + 8 * i + 4)).collect();
+        assert_eq!(flags, vec![sync, non_sync, sync, non_sync]);
+
+        // a fragment of sync samples only, and one starting without a sync sample
+        assert_eq!(default_sample_flags(&[sample(true), sample(true), sample(true)]), sync);
+        let samples = [sample(false), sample(false)];
+        assert_eq!(default_sample_flags(&samples), non_sync);
+        let mut buf = BytesMut::new();
+        write_trun(&mut buf, &samples, 0, 3000, non_sync);
+        assert_eq!(be32(find_box(&buf, b"trun"), 0) & 0xFFFFFF, 0x000201);
+    }
+
+    #[test]
+    fn empty_fragment() {
+        assert_eq!(default_sample_flags(&[]), 0);
+        let mut buf = BytesMut::new();
+        write_moof(&mut buf, 1, None, None, 0, 3000, &[], false);
+        assert_eq!(be32(find_box(&buf, b"trun"), 4), 0); // sample_count
+    }
+
+    #[test]
+    fn moof_relative_data_offset() {
+        let samples = [sample(true), sample(false), sample(true), sample(false)];
+        for (base_data_offset, sample_description_index, sdtp) in [(None, None, false), (Some(1000), None, false), (None, Some(2), true), (Some(1 << 40), Some(1), true)] {
+            let mut buf = BytesMut::new();
+            write_moof(&mut buf, 1, sample_description_index, base_data_offset, 0, 3000, &samples, sdtp);
+
+            // both bases are the first byte of the moof: the samples start after the moof and the mdat header
+            let trun = find_box(&buf, b"trun");
+            assert_eq!(be32(trun, 8) as usize, buf.len() + 8);
+
+            let tfhd = find_box(&buf, b"tfhd");
+            let flags = be32(tfhd, 0) & 0xFFFFFF;
+            match base_data_offset {
+                Some(offset) => {
+                    assert_eq!(flags & 0x020001, 0x000001);
+                    assert_eq!(u64::from(be32(tfhd, 8)) << 32 | u64::from(be32(tfhd, 12)), offset);
+                }
+                None => assert_eq!(flags & 0x020001, 0x020000),
+            }
+        }
+    }
+
+    fn reordered(is_sync: bool, composition_time_offset: i32) -> SampleInfo {
+        SampleInfo{composition_time_offset, ..sample(is_sync)}
+    }
+
+    #[test]
+    fn sidx_references() {
+        // I P B, then P I presented in decoding order, all with the same presentation delay
+        let fragments = [
+            (0, vec![reordered(true, 3000), reordered(false, 6000), reordered(false, 0)]),
+            (9000, vec![reordered(false, 3000), reordered(true, 3000)]),
+        ];
+        let mut references = vec![];
+        for (base_media_decode_time, samples) in &fragments {
+            let mut moof = BytesMut::new();
+            write_moof(&mut moof, 1, None, None, *base_media_decode_time, 3000, samples, false);
+            let mdat_size: u32 = samples.iter().map(|sample| sample.size).sum();
+            references.push(SegmentReference::new(samples, *base_media_decode_time, moof.len() as u32 + 8 + mdat_size));
+        }
+
+        // each subsegment ends at the earliest presentation time of the next one
+        assert_eq!((references[0].earliest_presentation_time, references[0].duration), (3000, 9000));
+        assert_eq!((references[1].earliest_presentation_time, references[1].duration), (12000, 6000));
+        assert_eq!((references[0].starts_with_sap, references[0].sap_type, references[0].sap_delta_time), (true, 1, 0));
+        assert_eq!((references[1].starts_with_sap, references[1].sap_type, references[1].sap_delta_time), (false, 1, 3000));
+        // a sample presented before the sync sample that precedes it in decoding order
+        let reference = SegmentReference::new(&[reordered(true, 6000), reordered(false, 0)], 0, 0);
+        assert_eq!((reference.sap_type, reference.earliest_presentation_time), (2, 3000));
+
+        let mut buf = BytesMut::new();
+        write_sidx(&mut buf, 90000, 0, &references);
+        assert_eq!(buf.len(), sidx_size(2));
+        let sidx = find_box(&buf, b"sidx");
+        assert_eq!(be32(sidx, 8), 90000);
+        assert_eq!(u64::from(be32(sidx, 12)) << 32 | u64::from(be32(sidx, 16)), 3000); // earliest_presentation_time
+        assert_eq!(be32(sidx, 28) & 0xFFFF, 2); // reference_count
+        for (i, reference) in references.iter().enumerate() {
+            let entry = 32 + 12 * i;
+            assert_eq!(be32(sidx, entry), reference.size); // reference_type 0, referenced_size
+            assert_eq!(be32(sidx, entry + 4), reference.duration);
+        }
+        assert_eq!(be32(sidx, 40), 0x9000_0000); // starts_with_SAP, SAP_type 1
+        assert_eq!(be32(sidx, 52), 0x1000_0000 | 3000);
+    }
+}
@@ -1,14 +1,29 @@
 use bytes::{BytesMut, BufMut};
 
+// parameter set NAL units of a sample description, without start codes, and the parsed SPS or sequence header
+pub enum DecoderConfig {
+    Avc { sps: Vec<u8>, pps: Vec<u8>, sps_ext: Option<Vec<u8>>, sps_info: crate::h264::Sps },
+    Hevc { vps: Vec<u8>, sps: Vec<u8>, pps: Vec<u8>, sps_info: crate::h265::Sps },
+    Av1 { sequence_header: Vec<u8>, info: crate::av1::SequenceHeader },  // sequence header OBU with its obu_size field
+}
+
+// one visual sample description and the picture size its parameter sets define
+pub struct SampleEntry {
+    pub config: DecoderConfig,
+    pub width: u16,
+    pub height: u16,
+}
+
 pub struct MoovInfo {
-    pub sps: Vec<u8>,
-    pub pps: Vec<u8>,
+    pub sample_entries: Vec<SampleEntry>,  // referenced by sample_description_index starting from 1
     pub width: u16,
     pub height: u16,
     pub horizontal_resolution: u32,
     pub vertical_resolution: u32,
     pub creation_time: u32,
     pub timescale: u32,
+    pub nal_length_size: u8,  // 1, 2 or 4 bytes before each NAL unit in the samples
+    pub in_band_parameter_sets: bool,  // avc3/hev1 instead of avc1/hvc1, samples may carry parameter sets
 }
 
 fn write_atom(parent: &mut BytesMut, id: &[u8; 4], atom: BytesMut) {
@@ -90,8 +105,8 @@ fn write_tkhd(parent: &mut BytesMut, moov_info: &MoovInfo) {
         buf.put_u32_be(0);
         buf.put_u32_be(1073741824);
     }
-    buf.put_u32_be(125829120);  // 4 Track width
-    buf.put_u32_be(70778880);  // 4 Track height
+    buf.put_u32_be((moov_info.width as u32) << 16);  // 4 Track width, 16.16 fixed point
+    buf.put_u32_be((moov_info.height as u32) << 16);  // 4 Track height, 16.16 fixed point
 
     write_atom(parent, b"tkhd", buf);
 }
@@ -177,13 +192,15 @@ fn write_stsd(parent: &mut BytesMut, moov_info: &MoovInfo) {
     let mut buf = BytesMut::with_capacity(1024);
     buf.put_u8(0);  // 1 version
     buf.put_u8(0); buf.put_u8(0); buf.put_u8(0);  // 3 flags
-    buf.put_u32_be(1); // 4  Number of entries
-    write_avc1(&mut buf, moov_info);
+    buf.put_u32_be(moov_info.sample_entries.len() as u32); // 4  Number of entries
+    for entry in &moov_info.sample_entries {
+        write_visual_sample_entry(&mut buf, moov_info, entry);
+    }
 
     write_atom(parent, b"stsd", buf);
 }
 
-fn write_avc1(parent: &mut BytesMut, moov_info: &MoovInfo) {
+fn write_visual_sample_entry(parent: &mut BytesMut, moov_info: &MoovInfo, entry: &SampleEntry) {
     let mut buf = BytesMut::with_capacity(1024);
 //    buf.put_u16_be(0);  // 1 version
 //    buf.put_u16_be(0);  // 1 revision
@@ -206,8 +223,8 @@ fn write_avc1(parent: &mut BytesMut, moov_info: &MoovInfo) {
     buf.put_u32_be(0);
     buf.put_u32_be(0);
     buf.put_u32_be(0); // pre_defined
-    buf.put_u16_be(moov_info.width); // 2 width
-    buf.put_u16_be(moov_info.height); // 2 height
+    buf.put_u16_be(entry.width); // 2 width
+    buf.put_u16_be(entry.height); // 2 height
     buf.put_u32_be(moov_info.horizontal_resolution); // 4 horizontal_resolution
     buf.put_u32_be(moov_info.vertical_resolution); // 4 vertical_resolution
     buf.put_u32_be(0); // reserved
@@ -223,32 +240,117 @@ fn write_avc1(parent: &mut BytesMut, moov_info: &MoovInfo) {
         0, 0, 0][..]); // compressorname
     buf.put_u16_be(24); // 2 depth
     buf.put_u16_be(0xffff); // 2 color_table_id
-    write_avcC(&mut buf, moov_info);
+    let sample_entry_type = match &entry.config {
+        DecoderConfig::Avc{sps, pps, sps_ext, sps_info} => {
+            write_avcC(&mut buf, moov_info, sps, pps, sps_ext.as_deref(), sps_info);
+            if moov_info.in_band_parameter_sets { b"avc3" } else { b"avc1" }
+        }
+        DecoderConfig::Hevc{vps, sps, pps, sps_info} => {
+            write_hvcC(&mut buf, moov_info, vps, sps, pps, sps_info);
+            if moov_info.in_band_parameter_sets { b"hev1" } else { b"hvc1" }
+        }
+        DecoderConfig::Av1{sequence_header, info} => {
+            write_av1C(&mut buf, sequence_header, info);
+            b"av01"
+        }
+    };
+    write_atom(parent, sample_entry_type, buf);
+}
 
-    write_atom(parent, b"avc1", buf);
+// ISO/IEC 14496-15 5.3.3.1.2: only the High profiles have chroma format, bit depths and SPS extensions in avcC
+pub fn avcc_has_sps_ext(profile_idc: u8) -> bool {
+    matches!(profile_idc, 100 | 110 | 122 | 244)
 }
 
 #[allow(non_snake_case)]
-fn write_avcC(parent: &mut BytesMut, moov_info: &MoovInfo) {
+fn write_avcC(parent: &mut BytesMut, moov_info: &MoovInfo, sps_nal: &[u8], pps_nal: &[u8], sps_ext_nal: Option<&[u8]>, sps: &crate::h264::Sps) {
     let mut buf = BytesMut::with_capacity(1024);
     buf.put_u8(1);  // 1 version
-    buf.put_u8(66);  // 1 profile
-    buf.put_u8(0);  // 1 compatibility
-    buf.put_u8(42);  // 1 level
+    buf.put_u8(sps_nal[1]);  // 1 profile (profile_idc)
+    buf.put_u8(sps_nal[2]);  // 1 compatibility (constraint_set flags)
+    buf.put_u8(sps_nal[3]);  // 1 level (level_idc)
 
-    buf.put_u8(0xFF);  // 6 bits reserved (111111) + 2 bits nal size length - 1 (11)
+    buf.put_u8(0xFC | (moov_info.nal_length_size - 1));  // 6 bits reserved (111111) + 2 bits nal size length - 1
     buf.put_u8(0xE1);  // 3 bits reserved (111) + 5 bits number of sps (00001)
 
-    buf.put_u16_be(moov_info.sps.len() as u16);
-    buf.put(moov_info.sps.as_slice()); // SPS
+    buf.put_u16_be(sps_nal.len() as u16);
+    buf.put(sps_nal); // SPS
 
     buf.put_u8(1);  // 1 num pps
-    buf.put_u16_be(moov_info.pps.len() as u16);
-    buf.put(moov_info.pps.as_slice()); // pps
+    buf.put_u16_be(pps_nal.len() as u16);
+    buf.put(pps_nal); // pps
+
+    if avcc_has_sps_ext(sps.profile_idc) {
+        buf.put_u8(0xFC | sps.chroma_format_idc as u8);  // 6 bits reserved (111111) + 2 bits chroma_format
+        buf.put_u8(0xF8 | sps.bit_depth_luma_minus8 as u8);  // 5 bits reserved (11111) + 3 bits bit_depth_luma_minus8
+        buf.put_u8(0xF8 | sps.bit_depth_chroma_minus8 as u8);  // 5 bits reserved (11111) + 3 bits bit_depth_chroma_minus8
+        match sps_ext_nal {
+            Some(nal) => {
+                buf.put_u8(1);  // 1 numOfSequenceParameterSetExt
+                buf.put_u16_be(nal.len() as u16);
+                buf.put(nal); // SPS extension
+            }
+            None => buf.put_u8(0),  // 1 numOfSequenceParameterSetExt
+        }
+    }
 
     write_atom(parent, b"avcC", buf);
 }
 
+// ISO/IEC 14496-15 8.3.3.1 HEVCDecoderConfigurationRecord
+#[allow(non_snake_case)]
+fn write_hvcC(parent: &mut BytesMut, moov_info: &MoovInfo, vps_nal: &[u8], sps_nal: &[u8], pps_nal: &[u8], sps: &crate::h265::Sps) {
+    let mut buf = BytesMut::with_capacity(1024);
+    let ptl = &sps.profile_tier_level;
+    buf.put_u8(1);  // 1 configurationVersion
+    buf.put_u8((ptl.general_profile_space << 6) | ((ptl.general_tier_flag as u8) << 5) | ptl.general_profile_idc);  // 2 bits general_profile_space + 1 bit general_tier_flag + 5 bits general_profile_idc
+    buf.put_u32_be(ptl.general_profile_compatibility_flags);  // 4 general_profile_compatibility_flags
+    buf.put_uint_be(ptl.general_constraint_indicator_flags, 6);  // 6 general_constraint_indicator_flags
+    buf.put_u8(ptl.general_level_idc);  // 1 general_level_idc
+    buf.put_u16_be(0xF000);  // 4 bits reserved (1111) + 12 bits min_spatial_segmentation_idc
+    buf.put_u8(0xFC);  // 6 bits reserved (111111) + 2 bits parallelismType
+    buf.put_u8(0xFC | sps.chroma_format_idc as u8);  // 6 bits reserved (111111) + 2 bits chromaFormat
+    buf.put_u8(0xF8 | sps.bit_depth_luma_minus8 as u8);  // 5 bits reserved (11111) + 3 bits bitDepthLumaMinus8
+    buf.put_u8(0xF8 | sps.bit_depth_chroma_minus8 as u8);  // 5 bits reserved (11111) + 3 bits bitDepthChromaMinus8
+    buf.put_u16_be(0);  // 2 avgFrameRate
+    buf.put_u8((((sps.sps_max_sub_layers_minus1 + 1) as u8) << 3)
+        | ((sps.sps_temporal_id_nesting_flag as u8) << 2)
+        | (moov_info.nal_length_size - 1));  // 2 bits constantFrameRate + 3 bits numTemporalLayers + 1 bit temporalIdNested + 2 bits lengthSizeMinusOne
+
+    buf.put_u8(3);  // 1 numOfArrays
+    for (nal_unit_type, nal) in [(32u8, vps_nal), (33, sps_nal), (34, pps_nal)] {
+        let array_completeness = if moov_info.in_band_parameter_sets { 0 } else { 0x80 };
+        buf.put_u8(array_completeness | nal_unit_type);  // 1 bit array_completeness + 1 bit reserved + 6 bits NAL_unit_type
+        buf.put_u16_be(1);  // 2 numNalus
+        buf.put_u16_be(nal.len() as u16);  // 2 nalUnitLength
+        buf.put(nal);
+    }
+
+    write_atom(parent, b"hvcC", buf);
+}
+
+// AV1 Codec ISO Media File Format Binding 2.3 AV1CodecConfigurationRecord
+#[allow(non_snake_case)]
+fn write_av1C(parent: &mut BytesMut, sequence_header_obu: &[u8], sh: &crate::av1::SequenceHeader) {
+    let mut buf = BytesMut::with_capacity(1024);
+    buf.put_u8(0x81);  // 1 bit marker + 7 bits version (1)
+    buf.put_u8((sh.seq_profile << 5) | sh.seq_level_idx_0);  // 3 bits seq_profile + 5 bits seq_level_idx_0
+    buf.put_u8(((sh.seq_tier_0 as u8) << 7)
+        | ((sh.high_bitdepth as u8) << 6)
+        | ((sh.twelve_bit as u8) << 5)
+        | ((sh.mono_chrome as u8) << 4)
+        | ((sh.subsampling_x as u8) << 3)
+        | ((sh.subsampling_y as u8) << 2)
+        | sh.chroma_sample_position);  // seq_tier_0, high_bitdepth, twelve_bit, monochrome, chroma_subsampling_x/y + 2 bits chroma_sample_position
+    match sh.initial_display_delay_minus_1 {
+        Some(delay) => buf.put_u8(0x10 | delay),  // 3 bits reserved + 1 bit initial_presentation_delay_present + 4 bits initial_presentation_delay_minus_one
+        None => buf.put_u8(0),
+    }
+    buf.put(sequence_header_obu);  // configOBUs
+
+    write_atom(parent, b"av1C", buf);
+}
+
 
 fn write_stts(parent: &mut BytesMut, ) {
     let mut buf = BytesMut::with_capacity(1024);
@@ -303,12 +405,14 @@ fn write_trex(parent: &mut BytesMut, ) {
     write_atom(parent, b"trex", buf);
 }
 
+#[allow(dead_code)] // its call in write_moov is commented out
 fn write_udta(parent: &mut BytesMut, ) {
     let mut buf = BytesMut::with_capacity(1024);
     write_meta(&mut buf);
     write_atom(parent, b"udta", buf);
 }
 
+#[allow(dead_code)]
 fn write_meta(parent: &mut BytesMut, ) {
     let mut buf = BytesMut::with_capacity(1024);
     buf.put_u8(0);  // 1 version
@@ -327,13 +431,14 @@ fn write_hdlr(parent: &mut BytesMut, name: &[u8; 4], value: &'static str, manufa
     buf.put(&manufacturer[..]); // 4 Component manufacturer
     buf.put_u32_be(0); // 4 Component flags
     buf.put_u32_be(0); // 4 Component flags mask
-    buf.put(&value[..]); // <counted string> Component name
+    buf.put(value); // <counted string> Component name
     buf.put_u8(0); // <counted string> end
     write_atom(parent, b"hdlr", buf);
 }
 
+#[allow(dead_code)]
 fn write_ilst(parent: &mut BytesMut, arr: &[u8]) {
     let mut buf = BytesMut::with_capacity(1024);
-    buf.put(&arr[..]); // <counted string> Component name
+    buf.put(arr); // <counted string> Component name
     write_atom(parent, b"ilst", buf);
 }
@@ -1,6 +1,6 @@
 use std::io::prelude::*;
 use std::io::Cursor;
-use bytes::{IntoBuf, BytesMut, BufMut, Buf};
+use bytes::Buf;
 
 
 #[derive(Debug, Clone, PartialEq)]
@@ -35,11 +35,9 @@ impl AtomType {
         if v == AtomType::MFRA as u32 { return Some(AtomType::MFRA); }
 
         panic!("Unknown type {}    str:{}", v, AtomType::u32_to_typestr(v));
-        None
     }
     fn u32_to_typestr(v: u32) -> String  {
-        let bytes: [u8; 4] = unsafe { std::mem::transmute(v.to_be()) };
-        String::from_utf8(bytes.to_vec()).unwrap()
+        String::from_utf8(v.to_be_bytes().to_vec()).unwrap()
     }
 }
 
@@ -73,7 +71,6 @@ pub struct Sample {
 
 pub fn read_sample(data: &mut Cursor<Vec<u8>>) -> Option<Sample> {
     let len = data.get_u32_be() as usize;
-    let len = len;
     let mut bytes = vec![0u8; len];
     let readed = data.read(&mut bytes).unwrap();
     // println!("readed {} len {} ", readed, len);
@@ -91,7 +88,6 @@ pub fn main_mp4_parser() -> std::io::Result<()> {
     file.read_to_end(&mut contents)?;
     let mut buf = Cursor::new(contents.clone());
 
-    let mut offset = 0;
     let mut count = 0;
     loop {
         if buf.position() as usize == contents.len() { break };
@@ -105,15 +101,17 @@ pub fn main_mp4_parser() -> std::io::Result<()> {
 
             let mut mdat = Cursor::new(atom.data.clone());
 
-            let mut offset = 0;
-            let mut count = 0;
+                    let mut count = 0;
             let mut all_size = 0;
             loop {
                 if mdat.position() as usize == atom.data.len() { break };
                 let sample = read_sample(&mut mdat);
                 if sample.is_none() { break; }
                 let sample= sample.unwrap();
-                let nal = crate::h264::nal_from_data(&sample.data).unwrap();
+                let nal = match crate::h264::nal_from_data(&sample.data) {
+                    Some(nal) => nal,
+                    None => { println!("         empty sample"); break; }
+                };
                 println!("         sample size = {:?},   nal = {:?}", sample.data.len(), nal.unit_type);
                 all_size += 4 + sample.data.len();
                 count += 1;
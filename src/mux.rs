@@ -0,0 +1,800 @@
+use std::io::prelude::*;
+use bytes::{BytesMut, BufMut};
+
+use crate::{av1, h264, h265, moof, moov};
+use crate::timestamps::{FrameTime, Timeline, Timestamp};
+
+// Frame rate used when the SPS has no VUI timing_info and no override is given
+pub const DEFAULT_FRAME_RATE: h264::FrameRate = h264::FrameRate{timescale: 90000, sample_duration: 3000};
+
+// What to do when the SPS or PPS changes in the middle of the stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterSetChange {
+    SampleDescription,  // add a sample description and select it in the tfhd of the following fragments. The moov
+                        // waits for every pair, it is moved before the fragments at the end.
+    InitSegment,        // write a new ftyp and moov before the following fragments, the default
+    NewFile,            // continue in a new output file, named after the first one with a _N suffix
+}
+
+// Where the decoder finds the parameter sets, hvc1 and hev1 are the H.265 counterparts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleEntryMode {
+    Avc1,   // only in avcC/hvcC: parameter sets, AUD and filler data are removed from the samples
+    Avc3,   // in-band: samples keep every NAL unit, for live streams whose parameter sets can change
+}
+
+// Where segment index boxes go
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SidxMode {
+    None,
+    OnDemand,   // one sidx after the init segment referencing every fragment, written once the file is complete
+    Live,       // a sidx with one reference before every fragment, each fragment is a segment
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    H264,
+    H265,
+    AV1,
+}
+
+impl Codec {
+    // .h265, .265 and .hevc files are H.265, .ivf, .obu and .av1 files AV1, anything else H.264
+    pub fn from_path(path: &str) -> Codec {
+        let extension = std::path::Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("h265") | Some("265") | Some("hevc") => Codec::H265,
+            Some("ivf") | Some("obu") | Some("av1") => Codec::AV1,
+            _ => Codec::H264,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MuxOptions {
+    // overrides the frame rate signalled in the SPS VUI timing_info
+    pub frame_rate: Option<h264::FrameRate>,
+    // presentation and optional decoding time of every access unit, e.g. from a timestamp file, for variable
+    // frame rate streams. The resilient mode keeps the times of the access units it drops.
+    pub timestamps: Option<Vec<FrameTime>>,
+    // input format, detected from the first bytes when None
+    pub input_format: Option<h264::StreamFormat>,
+    // size of the length field before each NAL unit in the samples: 1, 2 or 4
+    pub nal_length_size: u8,
+    pub on_parameter_set_change: ParameterSetChange,
+    pub sample_entry: SampleEntryMode,
+    // writes an sdtp box with the dependency flags of the samples next to every trun
+    pub sdtp: bool,
+    // drops corrupted NAL units and damaged pictures instead of muxing them, see h264::GopReader::resilient
+    pub resilient: bool,
+    // groups of pictures per fragment, a fragment has as many sync samples
+    pub gops_per_fragment: usize,
+    // sets default-base-is-moof in tfhd instead of an absolute base_data_offset, for CMAF and MSE segments
+    pub default_base_is_moof: bool,
+    pub sidx: SidxMode,
+}
+
+impl Default for MuxOptions {
+    fn default() -> MuxOptions {
+        MuxOptions{
+            frame_rate: None, timestamps: None, input_format: None, nal_length_size: 4,
+            on_parameter_set_change: ParameterSetChange::InitSegment,
+            sample_entry: SampleEntryMode::Avc1,
+            sdtp: false,
+            resilient: false,
+            gops_per_fragment: 1,
+            default_base_is_moof: false,
+            sidx: SidxMode::None,
+        }
+    }
+}
+
+impl MuxOptions {
+    // the NAL unit length fields of ISO/IEC 14496-15 are 1, 2 or 4 bytes long, in the input and in the samples
+    fn check(&self) -> std::io::Result<()> {
+        if let Some(h264::StreamFormat::Avcc(length_size)) = self.input_format {
+            if !matches!(length_size, 1 | 2 | 4) { return Err(invalid_data(&format!("invalid input NAL length size {}", length_size))); }
+        }
+        if !matches!(self.nal_length_size, 1 | 2 | 4) {
+            return Err(invalid_data(&format!("invalid NAL length size {}", self.nal_length_size)));
+        }
+        Ok(())
+    }
+}
+
+fn write_atom(parent: &mut BytesMut, id: &[u8; 4], atom: BytesMut) {
+    parent.put_u32_be(atom.len() as u32 + 8_u32);
+    parent.put_slice(&id[..]);
+    parent.put_slice(atom.as_ref());
+}
+
+fn write_ftyp(parent: &mut BytesMut, codec_brand: Option<&[u8; 4]>) {
+    let mut buf = BytesMut::with_capacity(1024);
+    buf.put(&b"isom"[..]);      // major_brand
+    buf.put_u32_be(0x00000200_u32);          // minor_version
+    buf.put(&b"isom"[..]);
+    buf.put(&b"iso2"[..]);
+    if let Some(brand) = codec_brand { buf.put(&brand[..]); }
+    buf.put(&b"iso6"[..]);
+    buf.put(&b"mp41"[..]);
+
+    write_atom(parent, b"ftyp", buf);
+}
+
+// Output file written box by box, so only the fragment being built is kept in memory
+pub struct Output {
+    writer: std::io::BufWriter<std::fs::File>,
+    path: String,
+    position: u64,  // bytes written so far
+    sidx: SidxMode,
+    timescale: u32, // of the track, set with the init segment
+    init_end: u64,  // position after the init segment, where the on-demand sidx goes
+    init_segment: Option<BytesMut>, // deferred init segment, inserted at the start of the file by finish
+    deferred: bool, // the init segment is only known once every fragment is written
+    references: Option<Vec<moof::SegmentReference>>, // fragments for the on-demand sidx
+}
+
+impl Output {
+    pub fn create(path: &str, sidx: SidxMode) -> std::io::Result<Output> {
+        let file = std::fs::File::create(path)?;
+        Ok(Output{
+            writer: std::io::BufWriter::new(file), path: path.to_string(), position: 0,
+            sidx, timescale: 0, init_end: 0, init_segment: None, deferred: false, references: None,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    // ftyp and moov
+    pub fn write_init_segment(&mut self, data: &[u8], timescale: u32) -> std::io::Result<()> {
+        self.write(data)?;
+        self.end_init_segment(timescale);
+        Ok(())
+    }
+
+    // the fragments are written first and the init segment given to set_init_segment before finish
+    pub fn defer_init_segment(&mut self, timescale: u32) {
+        self.deferred = true;
+        self.end_init_segment(timescale);
+    }
+
+    pub fn set_init_segment(&mut self, data: BytesMut) {
+        self.init_segment = Some(data);
+    }
+
+    // fragments moved after they are written need moof relative offsets
+    fn relocatable(&self) -> bool {
+        self.deferred || self.sidx == SidxMode::OnDemand
+    }
+
+    fn end_init_segment(&mut self, timescale: u32) {
+        self.timescale = timescale;
+        if self.sidx != SidxMode::OnDemand { return; }
+        if self.references.as_ref().is_some_and(|references| !references.is_empty()) {
+            // the references of a sidx have to be contiguous fragments
+            eprintln!("A new init segment ends the fragments the sidx references");
+            self.sidx = SidxMode::None;
+            return;
+        }
+        self.init_end = self.position;
+        self.references = Some(vec![]);
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        // the deferred init segment and the on-demand sidx go between the init segment and the first fragment
+        let mut header = self.init_segment.take().unwrap_or_default();
+        if self.sidx == SidxMode::OnDemand {
+            if let Some(references) = self.references.take().filter(|references| !references.is_empty()) {
+                header.reserve(moof::sidx_size(references.len()));
+                moof::write_sidx(&mut header, self.timescale, 0, &references);
+            }
+        }
+        if header.is_empty() { return Ok(()); }
+        drop(self.writer);
+
+        // the file is copied to insert them
+        let tmp = format!("{}.tmp", self.path);
+        std::fs::rename(&self.path, &tmp)?;
+        let mut src = std::fs::File::open(&tmp)?;
+        let mut dst = std::io::BufWriter::new(std::fs::File::create(&self.path)?);
+        std::io::copy(&mut (&mut src).take(self.init_end), &mut dst)?;
+        dst.write_all(&header)?;
+        std::io::copy(&mut src, &mut dst)?;
+        dst.flush()?;
+        std::fs::remove_file(&tmp)
+    }
+}
+
+fn write_mdat(out: &mut Output, data: &[u8]) -> std::io::Result<()> {
+    let mut header = BytesMut::with_capacity(8);
+    header.put_u32_be(data.len() as u32 + 8_u32);
+    header.put_slice(&b"mdat"[..]);
+    out.write(&header)?;
+    out.write(data)
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// tkhd and the visual sample entry store the picture size in 16 bits
+fn picture_size(width: u32, height: u32) -> std::io::Result<(u16, u16)> {
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(invalid_data(&format!("picture size {}x{} doesn't fit a sample entry", width, height)));
+    }
+    Ok((width as u16, height as u16))
+}
+
+// NAL units that an avc1 sample must not carry. SPS extensions stay in the samples when avcC has no room for them.
+fn out_of_band(nal: &h264::NAL, sps_ext_in_config: bool) -> bool {
+    use h264::NalUnitType::*;
+    matches!(nal.unit_type, SPS | PPS | AUD | Filler) || (nal.unit_type == SpsExt && sps_ext_in_config)
+}
+
+// NAL units that an hvc1 sample must not carry
+fn out_of_band_h265(nal: &h265::NAL) -> bool {
+    use h265::NalUnitType::*;
+    matches!(nal.unit_type, VPS | SPS | PPS | AUD | FD)
+}
+
+// samples are given as the NAL units they are made of, without start codes. Fails before anything
+// is written when there is no sample or a NAL unit is too long for the length field.
+fn write_samples(samples: &[Vec<&[u8]>], nal_length_size: u8) -> std::io::Result<(Vec<u32>, bytes::BytesMut)> {
+    if samples.is_empty() { return Err(invalid_data("fragment without samples")); }
+
+    let mut samples_sizes = vec![];
+
+    let mut all_size = 0;
+    for sample in samples {
+        if let Some(nal) = sample.iter().find(|nal| (nal.len() as u64) >> (8 * nal_length_size as u32) != 0) {
+            let message = format!("NAL unit of {} bytes doesn't fit a {} byte length field, use a larger --nal-length-size", nal.len(), nal_length_size);
+            return Err(invalid_data(&message));
+        }
+        let size: usize = sample.iter().map(|nal| nal.len() + nal_length_size as usize).sum();
+        samples_sizes.push(size as u32);
+        all_size += size;
+    }
+
+    let mut mdat_buf = BytesMut::with_capacity(all_size);
+    for sample in samples {
+        for nal in sample {
+            mdat_buf.put_uint_be(nal.len() as u64, nal_length_size as usize);  // NAL unit length
+            mdat_buf.put_slice(nal);
+        }
+    }
+    Ok((samples_sizes, mdat_buf))
+}
+
+// The size of a fragment as a sidx reference
+fn referenced_size(size: usize) -> std::io::Result<u32> {
+    if size > moof::MAX_REFERENCED_SIZE {
+        let message = format!("fragment of {} bytes is too large for a sidx, use a smaller --gops-per-fragment or leave out --sidx", size);
+        return Err(invalid_data(&message));
+    }
+    Ok(size as u32)
+}
+
+// Writes one moof and mdat with the samples of a group of pictures, timed by the decoding and presentation
+// time of every sample. next_dts is the decoding time of the sample after the group, it ends the last duration.
+#[allow(clippy::too_many_arguments)]
+pub fn write_fragment(out: &mut Output, sequence_number: u32, sample_description_index: Option<u32>, default_sample_duration: u32,
+                      samples_sizes: Vec<u32>, mdat_buf: BytesMut, timestamps: &[Timestamp], next_dts: u64,
+                      samples_flags: Vec<u32>, options: &MuxOptions) -> std::io::Result<()> {
+    // the on-demand sidx and a deferred init segment move the fragments after they are written,
+    // only moof relative offsets stay valid
+    let base_data_offset = match out.sidx {
+        _ if options.default_base_is_moof || out.relocatable() => None,
+        SidxMode::Live => Some(out.position + moof::sidx_size(1) as u64),
+        _ => Some(out.position),
+    };
+    if samples_sizes.is_empty() { return Err(invalid_data("fragment without samples")); }
+    let base_media_decode_time = timestamps[0].dts;
+
+    let mut samples_info = vec![];
+    for (i, (size, flags)) in samples_sizes.iter().zip(samples_flags).enumerate() {
+        let timestamp = timestamps[i];
+        let next = timestamps.get(i + 1).map_or(next_dts, |next| next.dts);
+        let sample_info = moof::SampleInfo {
+            size: *size,
+            duration: next.saturating_sub(timestamp.dts) as u32,
+            flags,
+            composition_time_offset: (timestamp.pts as i64 - timestamp.dts as i64) as i32,
+        };
+        samples_info.push(sample_info);
+    }
+
+    let mut buf = BytesMut::with_capacity(2*1024);
+    moof::write_moof(&mut buf, sequence_number, sample_description_index, base_data_offset, base_media_decode_time, default_sample_duration, &samples_info, options.sdtp);
+    let size = buf.len() + 8 + mdat_buf.len();
+    let size = if out.sidx == SidxMode::None { size as u32 } else { referenced_size(size)? };
+    let reference = moof::SegmentReference::new(&samples_info, base_media_decode_time, size);
+    match out.sidx {
+        SidxMode::OnDemand => if let Some(references) = &mut out.references { references.push(reference) },
+        SidxMode::Live => {
+            let mut sidx = BytesMut::with_capacity(moof::sidx_size(1));
+            moof::write_sidx(&mut sidx, out.timescale, 0, &[reference]);
+            out.write(&sidx)?;
+        }
+        SidxMode::None => {}
+    }
+    out.write(&buf)?;
+    write_mdat(out, &mdat_buf)
+}
+
+// IDR pictures and recovery points start the groups and are the sync samples,
+// pictures with nal_ref_idc equal to 0 are not referenced and can be dropped
+fn h264_sample_flags(au: &h264::AccessUnit) -> u32 {
+    moof::SampleFlags::new(au.is_random_access_point(), !au.is_idr, !au.is_reference).serialize()
+}
+
+// IRAP pictures are the sync samples, sub-layer non-reference pictures can be dropped
+fn h265_sample_flags(au: &h265::AccessUnit) -> u32 {
+    let disposable = au.nals.iter().find(|nal| nal.unit_type.is_vcl()).is_some_and(|nal| nal.unit_type.is_sub_layer_non_reference());
+    moof::SampleFlags::new(au.is_irap, !au.is_irap, disposable).serialize()
+}
+
+// the timestamps of the options if there are any, the frame rate times the frames otherwise
+fn timeline(options: &MuxOptions, frame_rate: h264::FrameRate) -> Timeline {
+    match &options.timestamps {
+        Some(frames) => Timeline::from_millis(frames, frame_rate),
+        None => Timeline::constant(frame_rate),
+    }
+}
+
+// timestamps of a group of frames given by their position in the stream, relative to the start
+// of the output file, and the decoding time of the next frame, the next-th of the stream
+fn group_timestamps(timeline: &Timeline, indices: &[usize], next: usize, composition_offsets: &[i32], start: u64) -> (Vec<Timestamp>, u64) {
+    let timestamps = timeline.group(indices, composition_offsets).into_iter()
+        .map(|t| Timestamp{dts: t.dts.saturating_sub(start), pts: t.pts.saturating_sub(start)})
+        .collect();
+    (timestamps, timeline.decode_time(next).saturating_sub(start))
+}
+
+// parameter set NAL units of an avc1/avc3 sample description
+#[derive(Debug, Clone, PartialEq)]
+struct AvcParameterSets {
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    sps_ext: Option<Vec<u8>>,
+}
+
+fn avc_sample_entry(sets: &AvcParameterSets) -> std::io::Result<moov::SampleEntry> {
+    let sps_info = h264::Sps::parse(&sets.sps).ok_or_else(|| invalid_data("can't parse the SPS"))?;
+    let (width, height) = picture_size(sps_info.width(), sps_info.height())?;
+    Ok(moov::SampleEntry{
+        config: moov::DecoderConfig::Avc{sps: sets.sps.clone(), pps: sets.pps.clone(), sps_ext: sets.sps_ext.clone(), sps_info},
+        width, height,
+    })
+}
+
+// SPS, PPS and SPS extension of a group of pictures
+fn parameter_sets(idr: &h264::IDR) -> std::io::Result<AvcParameterSets> {
+    match (&idr.sps, &idr.pps) {
+        (Some(sps), Some(pps)) => Ok(AvcParameterSets{
+            sps: sps.data.to_vec(), pps: pps.data.to_vec(), sps_ext: idr.sps_ext.as_ref().map(|nal| nal.data.to_vec()),
+        }),
+        _ => Err(invalid_data("group of pictures without SPS or PPS")),
+    }
+}
+
+// the SPS extension of the group goes in avcC rather than in the samples
+fn sps_ext_in_config(idr: &h264::IDR) -> bool {
+    idr.sps_ext.is_some() && idr.sps.as_ref().is_some_and(|sps| sps.data.len() > 1 && moov::avcc_has_sps_ext(sps.data[1]))
+}
+
+fn open_h264(input: &str, options: &MuxOptions) -> std::io::Result<h264::GopReader<std::fs::File>> {
+    options.check()?;
+    let gops = h264::GopReader::open(input, options.input_format)?;
+    Ok(if options.resilient { gops.resilient() } else { gops })
+}
+
+// ftyp and moov with one sample description for every SPS/PPS pair
+fn h264_init_segment(entries: &[AvcParameterSets], timescale: u32, options: &MuxOptions) -> std::io::Result<BytesMut> {
+    let sample_entries = entries.iter().map(avc_sample_entry).collect::<std::io::Result<Vec<_>>>()?;
+    let mut buf = BytesMut::with_capacity(4*1024);
+    write_ftyp(&mut buf, Some(b"avc1"));
+    let moov_info = moov::MoovInfo{
+        width: sample_entries[0].width, height: sample_entries[0].height,
+        sample_entries,
+        horizontal_resolution: 4718592, vertical_resolution: 4718592,
+        creation_time: 0, timescale,
+        nal_length_size: options.nal_length_size,
+        in_band_parameter_sets: options.sample_entry == SampleEntryMode::Avc3,
+    };
+    moov::write_moov(&mut buf, &moov_info);
+    Ok(buf)
+}
+
+// sample description of the SPS/PPS pair, selected in tfhd when the moov has more than one
+fn sample_description_index(entries: &[AvcParameterSets], pair: &AvcParameterSets) -> Option<u32> {
+    let index = entries.iter().position(|entry| entry == pair).unwrap_or(0);
+    if entries.len() > 1 { Some(index as u32 + 1) } else { None }
+}
+
+// writes the groups of pictures of one fragment, timed by the position of their access units in the stream.
+// next is the position of the access unit after the fragment, the last sample lasts until then.
+#[allow(clippy::too_many_arguments)]
+fn write_h264_fragment(out: &mut Output, sequence_number: u32, sample_description_index: Option<u32>, groups: &[h264::IDR],
+                       next: Option<usize>, timeline: &Timeline, file_start: u64, options: &MuxOptions) -> std::io::Result<()> {
+    let in_band = options.sample_entry == SampleEntryMode::Avc3;
+    let access_units: Vec<&h264::AccessUnit> = groups.iter().flat_map(|idr| &idr.samples).collect();
+    let samples: Vec<Vec<&[u8]>> = groups.iter().flat_map(|idr| {
+        let sps_ext_in_config = sps_ext_in_config(idr);
+        idr.samples.iter().map(move |au| {
+            au.nals.iter()
+                .filter(|nal| in_band || !out_of_band(nal, sps_ext_in_config))
+                .map(|nal| &nal.data[..])
+                .collect()
+        })
+    }).collect();
+    // pictures are reordered within their group only
+    let composition_offsets: Vec<i32> = groups.iter().flat_map(|idr| h264::composition_offsets(&idr.samples)).collect();
+    let indices: Vec<usize> = access_units.iter().map(|au| au.index).collect();
+    let next = next.unwrap_or_else(|| indices.last().map_or(0, |last| last + 1));
+    let (timestamps, next_dts) = group_timestamps(timeline, &indices, next, &composition_offsets, file_start);
+    let samples_flags = access_units.iter().map(|au| h264_sample_flags(au)).collect();
+    let (samples_sizes, mdat_buf) = write_samples(&samples, options.nal_length_size)?;
+    write_fragment(out, sequence_number, sample_description_index, timeline.frame_rate().sample_duration, samples_sizes, mdat_buf, &timestamps, next_dts, samples_flags, options)
+}
+
+// output.mp4, output_1.mp4, output_2.mp4, ...
+fn output_path(output: &str, index: usize) -> String {
+    if index == 0 { return output.to_string(); }
+    let path = std::path::Path::new(output);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, index),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+pub fn mux_h264(input: &str, output: &str, options: &MuxOptions) -> std::io::Result<()> {
+    let mut gops = open_h264(input, options)?;
+    // avc3 samples carry their own parameter sets, the first sample description is enough
+    let in_band = options.sample_entry == SampleEntryMode::Avc3;
+    // a single moov describes every SPS/PPS pair of the stream, it is written once they are all known
+    let deferred_moov = !in_band && options.on_parameter_set_change == ParameterSetChange::SampleDescription;
+
+    let mut out = Output::create(output, options.sidx)?;
+    let mut files = 1;
+    let mut timeline = Timeline::constant(DEFAULT_FRAME_RATE);
+    let mut entries = vec![];   // SPS/PPS pairs of the current moov
+    let mut prev = None;
+    let mut seq = 0_u32;
+    let mut file_start = 0_u64; // decoding time of the first frame of the output file
+    let mut fragment = vec![];  // groups of the next fragment
+    let mut fragment_index = None;
+    let mut fragments = 0;
+    for (count, idr) in gops.by_ref().enumerate() {
+        let pair = parameter_sets(&idr)?;
+        let changed = prev.as_ref().is_some_and(|prev| *prev != pair);
+        if changed { eprintln!("Parameter sets changed at group {}", count); }
+        let new_init = prev.is_none() || (changed && !in_band && options.on_parameter_set_change != ParameterSetChange::SampleDescription);
+        if deferred_moov && !entries.contains(&pair) { entries.push(pair.clone()); }
+
+        // the samples of a fragment share the init segment and sample description
+        if !fragment.is_empty() && (new_init || fragment.len() >= options.gops_per_fragment || sample_description_index(&entries, &pair) != fragment_index) {
+            seq += 1;
+            write_h264_fragment(&mut out, seq, fragment_index, &fragment, Some(idr.samples[0].index), &timeline, file_start, options)?;
+            fragments += 1;
+            fragment.clear();
+        }
+
+        if new_init {
+            if prev.is_none() {
+                let sps_info = h264::Sps::parse(&pair.sps).ok_or_else(|| invalid_data("can't parse the SPS"))?;
+                let frame_rate = options.frame_rate.or_else(|| sps_info.frame_rate()).unwrap_or(DEFAULT_FRAME_RATE);
+                timeline = self::timeline(options, frame_rate);
+            } else if options.on_parameter_set_change == ParameterSetChange::NewFile {
+                out.finish()?;
+                out = Output::create(&output_path(output, files), options.sidx)?;
+                files += 1;
+                seq = 0;
+                file_start = timeline.decode_time(idr.samples[0].index);
+            }
+            let timescale = timeline.frame_rate().timescale;
+            if deferred_moov {
+                out.defer_init_segment(timescale);
+            } else {
+                entries = vec![pair.clone()];
+                out.write_init_segment(&h264_init_segment(&entries, timescale, options)?, timescale)?;
+            }
+        }
+
+        fragment_index = sample_description_index(&entries, &pair);
+        fragment.push(idr);
+        prev = Some(pair);
+    }
+    if !fragment.is_empty() {
+        write_h264_fragment(&mut out, seq + 1, fragment_index, &fragment, None, &timeline, file_start, options)?;
+        fragments += 1;
+    }
+    eprintln!("mdats: {} ", fragments);
+
+    if options.resilient {
+        eprintln!("Corruption report: {} entries", gops.corruptions().len());
+        for corruption in gops.corruptions() {
+            eprintln!("  offset {}: dropped {} bytes, {:?}", corruption.offset, corruption.size, corruption.damage);
+        }
+    }
+
+    if deferred_moov && !entries.is_empty() {
+        out.set_init_segment(h264_init_segment(&entries, timeline.frame_rate().timescale, options)?);
+    }
+    // what was read is muxed, the run still fails
+    let read_error = gops.take_error();
+    out.finish()?;
+    read_error.map_or(Ok(()), Err)
+}
+
+// writes the groups of one fragment, the first of them being the first-th frame of the stream, timed
+// from file_start, the decoding time of the first frame of the output file. Returns the number of frames written.
+fn write_h265_fragment(out: &mut Output, sequence_number: u32, groups: &[h265::Gop], timeline: &Timeline, first: usize,
+                       file_start: u64, options: &MuxOptions) -> std::io::Result<usize> {
+    let access_units: Vec<&h265::AccessUnit> = groups.iter().flat_map(|gop| &gop.samples).collect();
+    let samples: Vec<Vec<&[u8]>> = access_units.iter().map(|au| {
+        au.nals.iter()
+            .filter(|nal| options.sample_entry == SampleEntryMode::Avc3 || !out_of_band_h265(nal))
+            .map(|nal| &nal.data[..])
+            .collect()
+    }).collect();
+    let composition_offsets: Vec<i32> = groups.iter().flat_map(|gop| h265::composition_offsets(&gop.samples)).collect();
+    let indices: Vec<usize> = (first .. first + access_units.len()).collect();
+    let (timestamps, next_dts) = group_timestamps(timeline, &indices, first + indices.len(), &composition_offsets, file_start);
+    let samples_flags = access_units.iter().map(|au| h265_sample_flags(au)).collect();
+    let (samples_sizes, mdat_buf) = write_samples(&samples, options.nal_length_size)?;
+    write_fragment(out, sequence_number, None, timeline.frame_rate().sample_duration, samples_sizes, mdat_buf, &timestamps, next_dts, samples_flags, options)?;
+    Ok(samples.len())
+}
+
+// ftyp and moov with the VPS, SPS and PPS of a group in hvcC
+fn h265_init_segment(parameter_sets: &(h265::NAL, h265::NAL, h265::NAL), timescale: u32, options: &MuxOptions) -> std::io::Result<BytesMut> {
+    let (vps, sps, pps) = parameter_sets;
+    let sps_info = h265::Sps::parse(&sps.data).ok_or_else(|| invalid_data("can't parse the SPS"))?;
+    let (width, height) = picture_size(sps_info.width(), sps_info.height())?;
+    let mut buf = BytesMut::with_capacity(4*1024);
+    write_ftyp(&mut buf, None);
+    let moov_info = moov::MoovInfo{
+        sample_entries: vec![moov::SampleEntry{
+            config: moov::DecoderConfig::Hevc{vps: vps.data.to_vec(), sps: sps.data.to_vec(), pps: pps.data.to_vec(), sps_info},
+            width, height,
+        }],
+        width, height,
+        horizontal_resolution: 4718592, vertical_resolution: 4718592,
+        creation_time: 0, timescale,
+        nal_length_size: options.nal_length_size,
+        in_band_parameter_sets: options.sample_entry == SampleEntryMode::Avc3,
+    };
+    moov::write_moov(&mut buf, &moov_info);
+    Ok(buf)
+}
+
+pub fn mux_h265(input: &str, output: &str, options: &MuxOptions) -> std::io::Result<()> {
+    options.check()?;
+    let mut gops = h265::GopReader::open(input, options.input_format)?;
+    let first = gops.next().ok_or_else(|| invalid_data("no picture in the stream"))?;
+    let mut sets = first.parameter_sets().cloned().ok_or_else(|| invalid_data("no picture with its VPS, SPS and PPS"))?;
+    // hev1 samples carry their own parameter sets, the first hvcC is enough
+    let in_band = options.sample_entry == SampleEntryMode::Avc3;
+
+    let vps_info = h265::Vps::parse(&sets.0.data).ok_or_else(|| invalid_data("can't parse the VPS"))?;
+    let frame_rate = options.frame_rate.or_else(|| vps_info.frame_rate()).unwrap_or(DEFAULT_FRAME_RATE);
+    let timeline = timeline(options, frame_rate);
+    let timescale = timeline.frame_rate().timescale;
+
+    let mut out = Output::create(output, options.sidx)?;
+    out.write_init_segment(&h265_init_segment(&sets, timescale, options)?, timescale)?;
+
+    let mut files = 1;
+    let mut seq = 0;
+    let mut frames = 0;
+    let mut file_start = 0_u64; // decoding time of the first frame of the output file
+    let mut fragment = vec![first]; // groups of the next fragment
+    for (count, gop) in gops.by_ref().enumerate() {
+        let data = |(vps, sps, pps): &(h265::NAL, h265::NAL, h265::NAL)| (vps.data.clone(), sps.data.clone(), pps.data.clone());
+        let changed = gop.parameter_sets().filter(|next| data(next) != data(&sets)).cloned();
+        if changed.is_some() { eprintln!("Parameter sets changed at group {}", count + 1); }
+        let new_init = changed.is_some() && !in_band;
+        if new_init && options.on_parameter_set_change == ParameterSetChange::SampleDescription {
+            return Err(invalid_data("the parameter sets change, hvc1 has one sample description: use --hev1 or --on-parameter-change init"));
+        }
+
+        if new_init || fragment.len() >= options.gops_per_fragment {
+            seq += 1;
+            frames += write_h265_fragment(&mut out, seq, &fragment, &timeline, frames, file_start, options)?;
+            fragment.clear();
+        }
+        if let Some(changed) = changed {
+            sets = changed;
+            if new_init {
+                if options.on_parameter_set_change == ParameterSetChange::NewFile {
+                    out.finish()?;
+                    out = Output::create(&output_path(output, files), options.sidx)?;
+                    files += 1;
+                    seq = 0;
+                    file_start = timeline.decode_time(frames);
+                }
+                out.write_init_segment(&h265_init_segment(&sets, timescale, options)?, timescale)?;
+            }
+        }
+        fragment.push(gop);
+    }
+    write_h265_fragment(&mut out, seq + 1, &fragment, &timeline, frames, file_start, options)?;
+    eprintln!("mdats: {} ", seq + 1);
+
+    let read_error = gops.take_error();
+    out.finish()?;
+    read_error.map_or(Ok(()), Err)
+}
+
+// writes the groups of one fragment, the first of them being the first-th frame of the stream.
+// Returns the number of frames written.
+fn write_av1_fragment(out: &mut Output, sequence_number: u32, groups: &[av1::Gop], timeline: &Timeline, first: usize,
+                      options: &MuxOptions) -> std::io::Result<usize> {
+    let units: Vec<&av1::TemporalUnit> = groups.iter().flat_map(|gop| &gop.samples).collect();
+    let mut samples_sizes = vec![];
+    let mut mdat_buf = BytesMut::new();
+    for unit in &units {
+        let sample = unit.sample();
+        samples_sizes.push(sample.len() as u32);
+        mdat_buf.extend_from_slice(&sample);
+    }
+    // temporal units are stored in presentation order
+    let composition_offsets = vec![0; samples_sizes.len()];
+    let indices: Vec<usize> = (first .. first + units.len()).collect();
+    let (timestamps, next_dts) = group_timestamps(timeline, &indices, first + indices.len(), &composition_offsets, 0);
+    // key frames are the sync samples, the reference structure of the other frames is not parsed
+    let samples_flags = units.iter().map(|unit| moof::SampleFlags::new(unit.is_key, !unit.is_key, false).serialize()).collect();
+    write_fragment(out, sequence_number, None, timeline.frame_rate().sample_duration, samples_sizes, mdat_buf, &timestamps, next_dts, samples_flags, options)?;
+    Ok(units.len())
+}
+
+pub fn mux_av1(input: &str, output: &str, options: &MuxOptions) -> std::io::Result<()> {
+    let mut gops = av1::GopReader::open(input)?;
+    let first = match gops.next() {
+        Some(first) => first,
+        None => return Err(gops.take_error().unwrap_or_else(|| invalid_data("no temporal unit in the stream"))),
+    };
+    let (sequence_header, sequence_header_info) = gops.sequence_header().cloned().ok_or_else(|| invalid_data("no sequence header OBU"))?;
+
+    let frame_rate = options.frame_rate.or_else(|| sequence_header_info.frame_rate()).or_else(|| gops.ivf_frame_rate()).unwrap_or(DEFAULT_FRAME_RATE);
+    let timeline = timeline(options, frame_rate);
+
+    let mut out = Output::create(output, options.sidx)?;
+    let mut buf = BytesMut::with_capacity(4*1024);
+    write_ftyp(&mut buf, Some(b"av01"));
+    let (width, height) = picture_size(sequence_header_info.width(), sequence_header_info.height())?;
+    let mut sequence_header_obu = vec![];
+    sequence_header.write(&mut sequence_header_obu);
+    let moov_info = moov::MoovInfo{
+        sample_entries: vec![moov::SampleEntry{
+            config: moov::DecoderConfig::Av1{sequence_header: sequence_header_obu, info: sequence_header_info},
+            width, height,
+        }],
+        width, height,
+        horizontal_resolution: 4718592, vertical_resolution: 4718592,
+        creation_time: 0, timescale: timeline.frame_rate().timescale,
+        nal_length_size: options.nal_length_size,
+        in_band_parameter_sets: false,
+    };
+    moov::write_moov(&mut buf, &moov_info);
+    out.write_init_segment(&buf, moov_info.timescale)?;
+
+    let mut seq = 0;
+    let mut frames = 0;
+    let mut fragment = vec![first]; // groups of the next fragment
+    for gop in gops.by_ref() {
+        if fragment.len() >= options.gops_per_fragment {
+            seq += 1;
+            frames += write_av1_fragment(&mut out, seq, &fragment, &timeline, frames, options)?;
+            fragment.clear();
+        }
+        fragment.push(gop);
+    }
+    write_av1_fragment(&mut out, seq + 1, &fragment, &timeline, frames, options)?;
+    eprintln!("mdats: {} ", seq + 1);
+
+    // what was read is muxed, the run still fails
+    let read_error = gops.take_error();
+    out.finish()?;
+    read_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{STREAM, be32, find_box};
+
+    #[test]
+    fn referenced_size_of_31_bits() {
+        assert_eq!(referenced_size(moof::MAX_REFERENCED_SIZE).unwrap(), 0x7FFF_FFFF);
+        let error = referenced_size(moof::MAX_REFERENCED_SIZE + 1).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sample_description_per_parameter_sets() {
+        let first = AvcParameterSets{sps: vec![0x67, 1], pps: vec![0x68, 1], sps_ext: None};
+        let second = AvcParameterSets{sps: vec![0x67, 2], ..first.clone()};
+        assert_eq!(sample_description_index(std::slice::from_ref(&first), &first), None);
+        let entries = [first.clone(), second.clone()];
+        assert_eq!(sample_description_index(&entries, &first), Some(1));
+        assert_eq!(sample_description_index(&entries, &second), Some(2));
+    }
+
+    #[test]
+    fn invalid_nal_length_size() {
+        let options = MuxOptions{nal_length_size: 3, ..Default::default()};
+        assert_eq!(mux_h264(STREAM, "invalid.mp4", &options).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        let options = MuxOptions{input_format: Some(h264::StreamFormat::Avcc(0)), ..Default::default()};
+        assert_eq!(mux_h265(STREAM, "invalid.mp4", &options).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(!std::path::Path::new("invalid.mp4").exists());
+    }
+
+    #[test]
+    fn fragment_without_samples() {
+        assert_eq!(write_samples(&[], 4).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn stsd_with_two_entries() {
+        let mut gops = h264::GopReader::open(STREAM, None).unwrap();
+        let sets = parameter_sets(&gops.next().unwrap()).unwrap();
+        let mut other_sps = sets.clone();
+        other_sps.sps[3] = 40; // level_idc
+
+        let sample_entries = vec![avc_sample_entry(&sets).unwrap(), avc_sample_entry(&other_sps).unwrap()];
+        let moov_info = moov::MoovInfo{
+            width: 1920, height: 1080, sample_entries,
+            horizontal_resolution: 4718592, vertical_resolution: 4718592,
+            creation_time: 0, timescale: 90000, nal_length_size: 4, in_band_parameter_sets: false,
+        };
+        let mut buf = BytesMut::with_capacity(4*1024);
+        moov::write_moov(&mut buf, &moov_info);
+
+        let stsd = find_box(&buf, b"stsd");
+        assert_eq!(&stsd[.. 8], &[0, 0, 0, 0, 0, 0, 0, 2]); // version, flags, entry_count
+        let mut entry = 8;
+        for level_idc in [42, 40] {
+            let size = be32(stsd, entry) as usize;
+            assert_eq!(&stsd[entry + 4 .. entry + 8], b"avc1");
+            let avcc = find_box(&stsd[entry .. entry + size], b"avcC");
+            assert_eq!(avcc[3], level_idc); // AVCLevelIndication
+            entry += size;
+        }
+    }
+
+    #[test]
+    fn sps_ext_in_avcc() {
+        // High profile 4:2:0 8 bit 32x32 SPS and an SPS extension of seq_parameter_set_id 0
+        let mut w = crate::rbsp::BitWriter::new();
+        w.write_bits(8, 100); w.write_bits(8, 0); w.write_bits(8, 40); w.write_ue(0); // profile_idc, constraint flags, level_idc, id
+        w.write_ue(1); w.write_ue(0); w.write_ue(0); w.write_bit(false); w.write_bit(false); // chroma_format_idc, bit depths, no scaling matrix
+        w.write_ue(0); w.write_ue(0); w.write_ue(0); w.write_ue(1); w.write_bit(false); // frame_num, POC type 0 and lsb sizes, refs, no gaps
+        w.write_ue(1); w.write_ue(1); w.write_bit(true); w.write_bit(true); w.write_bit(false); w.write_bit(false); // 2x2 MBs, frames, no cropping, no VUI
+        w.write_rbsp_trailing_bits();
+        let mut sps = vec![0x67];
+        sps.extend(w.into_bytes());
+        let sets = AvcParameterSets{sps, pps: vec![0x68, 0xCE], sps_ext: Some(vec![0x6D, 0x88, 0x80])};
+
+        let moov_info = moov::MoovInfo{
+            width: 32, height: 32, sample_entries: vec![avc_sample_entry(&sets).unwrap()],
+            horizontal_resolution: 4718592, vertical_resolution: 4718592,
+            creation_time: 0, timescale: 90000, nal_length_size: 4, in_band_parameter_sets: false,
+        };
+        let mut buf = BytesMut::with_capacity(4*1024);
+        moov::write_moov(&mut buf, &moov_info);
+        let avcc = find_box(&buf, b"avcC");
+        // chroma_format, bit depths, numOfSequenceParameterSetExt, its length and the NAL unit
+        assert_eq!(&avcc[avcc.len() - 9 ..], &[0xFD, 0xF8, 0xF8, 1, 0, 3, 0x6D, 0x88, 0x80]);
+
+        let ext = h264::NAL::new(0, 3, bytes::Bytes::from(vec![0x6D, 0x88, 0x80])).unwrap();
+        assert!(out_of_band(&ext, true) && !out_of_band(&ext, false));
+    }
+}
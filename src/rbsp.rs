@@ -0,0 +1,232 @@
+// 7.3.1 / 7.4.1: conversion between the encapsulated byte sequence payload (EBSP) of a NAL unit
+// and its raw byte sequence payload (RBSP), and bit level reading and writing of the RBSP syntax
+
+// Removes every emulation_prevention_three_byte (0x03 following two zero bytes)
+pub fn ebsp_to_rbsp(ebsp: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(ebsp.len());
+    let mut zeros = 0;
+    for &b in ebsp {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0x00 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+// Inserts an emulation_prevention_three_byte wherever two zero bytes are followed by 0x00..0x03
+#[cfg(test)]
+pub fn rbsp_to_ebsp(rbsp: &[u8]) -> Vec<u8> {
+    let mut ebsp = Vec::with_capacity(rbsp.len() + rbsp.len() / 64);
+    let mut zeros = 0;
+    for &b in rbsp {
+        if zeros >= 2 && b <= 0x03 {
+            ebsp.push(0x03);
+            zeros = 0;
+        }
+        zeros = if b == 0x00 { zeros + 1 } else { 0 };
+        ebsp.push(b);
+    }
+    // the RBSP can only end with 0x00 in a cabac_zero_word, which is followed by 0x03
+    if rbsp.last() == Some(&0x00) { ebsp.push(0x03); }
+    ebsp
+}
+
+pub struct BitReader<'a> { // 7.2 Specification of syntax functions, categories, and descriptors
+    data: &'a [u8],
+    pos: usize, // position in bits
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> { BitReader{data, pos: 0} }
+
+    pub fn bits_left(&self) -> usize { self.data.len() * 8 - self.pos }
+
+    pub fn read_bit(&mut self) -> Option<bool> { // u(1)
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 0x01;
+        self.pos += 1;
+        Some(bit == 1)
+    }
+
+    pub fn read_bits(&mut self, n: u8) -> Option<u32> { // u(n), n <= 32
+        let mut v = 0u32;
+        for _ in 0 .. n {
+            v = (v << 1) | self.read_bit()? as u32;
+        }
+        Some(v)
+    }
+
+    pub fn skip_bits(&mut self, n: usize) -> Option<()> {
+        if n > self.bits_left() { return None; }
+        self.pos += n;
+        Some(())
+    }
+
+    pub fn read_ue(&mut self) -> Option<u32> { // ue(v)  9.1 Parsing process for Exp-Golomb codes
+        let mut leading_zero_bits = 0u8;
+        while !self.read_bit()? {
+            leading_zero_bits += 1;
+            if leading_zero_bits > 31 { return None; }
+        }
+        let suffix = self.read_bits(leading_zero_bits)? as u64;
+        Some(((1u64 << leading_zero_bits) - 1 + suffix) as u32)
+    }
+
+    pub fn read_se(&mut self) -> Option<i32> { // se(v)  9.1.1 Table 9-3
+        let k = self.read_ue()? as i64;
+        if k % 2 == 1 { Some(((k + 1) / 2) as i32) } else { Some((-(k / 2)) as i32) }
+    }
+
+    pub fn more_rbsp_data(&self) -> bool { // 7.2  more_rbsp_data()
+        // the last bit equal to 1 in the payload is the rbsp_stop_one_bit
+        let last = match self.data.iter().rposition(|b| *b != 0) {
+            Some(last) => last,
+            None => return false,
+        };
+        let stop_bit = last * 8 + 7 - self.data[last].trailing_zeros() as usize;
+        self.pos < stop_bit
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    bits: u8, // number of bits used in the last byte
+}
+
+#[cfg(test)]
+impl BitWriter {
+    pub fn new() -> BitWriter { BitWriter::default() }
+
+    pub fn write_bit(&mut self, bit: bool) { // u(1)
+        if self.bits == 0 { self.data.push(0); }
+        if bit { *self.data.last_mut().unwrap() |= 0x80 >> self.bits; }
+        self.bits = (self.bits + 1) % 8;
+    }
+
+    pub fn write_bits(&mut self, n: u8, v: u32) { // u(n), n <= 32
+        for i in (0 .. n).rev() {
+            self.write_bit((v >> i) & 0x01 == 1);
+        }
+    }
+
+    pub fn write_ue(&mut self, v: u32) { // ue(v)
+        let v = v as u64 + 1;
+        let len = 64 - v.leading_zeros() as u8; // bits of codeNum + 1
+        for _ in 1 .. len { self.write_bit(false); }
+        for i in (0 .. len).rev() {
+            self.write_bit((v >> i) & 0x01 == 1);
+        }
+    }
+
+    pub fn write_se(&mut self, v: i32) { // se(v)
+        let k = if v > 0 { 2 * v as i64 - 1 } else { -2 * v as i64 };
+        self.write_ue(k as u32);
+    }
+
+    pub fn write_rbsp_trailing_bits(&mut self) { // 7.3.2.11
+        self.write_bit(true); // rbsp_stop_one_bit
+        while self.bits != 0 { self.write_bit(false); } // rbsp_alignment_zero_bit
+    }
+
+    pub fn is_byte_aligned(&self) -> bool { self.bits == 0 }
+
+    pub fn into_bytes(self) -> Vec<u8> { self.data }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::{NalUnitType, Sps};
+    use crate::testing::stream_nals;
+
+    #[test]
+    fn emulation_prevention() {
+        assert_eq!(ebsp_to_rbsp(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x03]), vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x03]);
+        assert_eq!(rbsp_to_ebsp(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x03]), vec![0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x03]);
+        assert_eq!(rbsp_to_ebsp(&[0x00, 0x00, 0x04]), vec![0x00, 0x00, 0x04]);
+        assert_eq!(rbsp_to_ebsp(&[0x80, 0x00, 0x00]), vec![0x80, 0x00, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let nals = stream_nals();
+        assert!(!nals.is_empty());
+        let mut with_emulation_prevention = 0;
+        for nal in &nals {
+            let rbsp = ebsp_to_rbsp(&nal.data[1 ..]);
+            if rbsp.len() != nal.data.len() - 1 { with_emulation_prevention += 1; }
+            assert_eq!(rbsp_to_ebsp(&rbsp), &nal.data[1 ..], "NAL at {}", nal.start);
+        }
+        assert!(with_emulation_prevention > 0);
+    }
+
+    #[test]
+    fn stream_sps() {
+        let nals = stream_nals();
+        let sps = nals.iter().find(|nal| nal.unit_type == NalUnitType::SPS).unwrap();
+        let sps = Sps::parse(&sps.data).unwrap();
+        assert_eq!((sps.profile_idc, sps.level_idc), (66, 42));
+        assert_eq!((sps.width(), sps.height()), (1920, 1080));
+        assert_eq!((sps.coded_width(), sps.coded_height()), (1920, 1088));
+    }
+
+    #[test]
+    fn stream_pps_trailing_bits() {
+        let nals = stream_nals();
+        let pps = nals.iter().find(|nal| nal.unit_type == NalUnitType::PPS).unwrap();
+        let rbsp = ebsp_to_rbsp(&pps.data[1 ..]);
+        let mut r = BitReader::new(&rbsp);
+        assert_eq!(r.read_ue(), Some(0)); // pic_parameter_set_id
+        assert_eq!(r.read_ue(), Some(0)); // seq_parameter_set_id
+        assert!(r.more_rbsp_data());
+        while r.more_rbsp_data() { r.read_bit().unwrap(); }
+        assert_eq!(r.read_bit(), Some(true)); // rbsp_stop_one_bit
+    }
+
+    #[test]
+    fn write_read_exp_golomb() {
+        let values = [0u32, 1, 2, 3, 7, 8, 255, 256, 65535, 1 << 20, u32::MAX - 1];
+        let signed = [0i32, 1, -1, 2, -2, 100, -100, i32::MAX, i32::MIN + 1];
+        let mut w = BitWriter::new();
+        for v in values.iter() { w.write_ue(*v); }
+        for v in signed.iter() { w.write_se(*v); }
+        w.write_bits(5, 0b10110);
+        w.write_rbsp_trailing_bits();
+        assert!(w.is_byte_aligned());
+        let data = w.into_bytes();
+
+        let mut r = BitReader::new(&data);
+        for v in values.iter() { assert_eq!(r.read_ue(), Some(*v)); }
+        for v in signed.iter() { assert_eq!(r.read_se(), Some(*v)); }
+        assert_eq!(r.read_bits(5), Some(0b10110));
+        assert!(!r.more_rbsp_data());
+    }
+
+    #[test]
+    fn rewrite_stream_sps() {
+        // re-encoding the fields read from the SPS gives back the original NAL unit
+        let nals = stream_nals();
+        let nal = nals.iter().find(|nal| nal.unit_type == NalUnitType::SPS).unwrap();
+        let rbsp = ebsp_to_rbsp(&nal.data[1 ..]);
+        let mut r = BitReader::new(&rbsp);
+        let mut w = BitWriter::new();
+        w.write_bits(24, r.read_bits(24).unwrap()); // profile_idc, constraint flags, level_idc
+        for _ in 0 .. 2 { w.write_ue(r.read_ue().unwrap()); } // seq_parameter_set_id, log2_max_frame_num_minus4
+        let pic_order_cnt_type = r.read_ue().unwrap();
+        w.write_ue(pic_order_cnt_type);
+        assert_eq!(pic_order_cnt_type, 2);
+        w.write_ue(r.read_ue().unwrap()); // max_num_ref_frames
+        w.write_bit(r.read_bit().unwrap()); // gaps_in_frame_num_value_allowed_flag
+        for _ in 0 .. 2 { w.write_ue(r.read_ue().unwrap()); } // pic_width_in_mbs_minus1, pic_height_in_map_units_minus1
+        while r.more_rbsp_data() { w.write_bit(r.read_bit().unwrap()); }
+        w.write_rbsp_trailing_bits();
+        let mut rewritten = vec![nal.data[0]];
+        rewritten.extend(rbsp_to_ebsp(&w.into_bytes()));
+        assert_eq!(rewritten, nal.data);
+    }
+}
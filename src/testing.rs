@@ -0,0 +1,24 @@
+// Helpers shared by the unit tests
+
+use crate::h264::{AnnexBParser, NAL};
+
+// H.264 Annex B stream of the repository: 235 single slice pictures in 8 groups
+pub const STREAM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/stream_chn0.h264");
+
+pub fn stream_nals() -> Vec<NAL> {
+    let data = std::fs::read(STREAM).unwrap();
+    let mut parser = AnnexBParser::new();
+    let mut nals = parser.push(&data);
+    nals.extend(parser.finish());
+    nals
+}
+
+pub fn be32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+// payload of the first box of the given type, after its size and type
+pub fn find_box<'a>(data: &'a [u8], id: &[u8; 4]) -> &'a [u8] {
+    let start = data.windows(4).position(|w| w == id).unwrap() - 4;
+    &data[start + 8 .. start + be32(data, start) as usize]
+}
@@ -0,0 +1,171 @@
+use crate::h264::FrameRate;
+
+// Timescale of tracks timed by a timestamp file, 90 kHz as in MPEG-2 systems
+pub const TIMESTAMPS_TIMESCALE: u32 = 90000;
+
+// Decoding and presentation time of a sample in the track timescale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+    pub dts: u64,
+    pub pts: u64,
+}
+
+// Times of one access unit in milliseconds, e.g. a line of a timestamp file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameTime {
+    pub pts: f64,
+    pub dts: Option<f64>, // decoding time when the source has one, derived from the presentation times otherwise
+}
+
+// Presentation times of the frames of a track in presentation order. Frames past the end of the list,
+// or all frames when there is no list, follow each other at the frame rate.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    times: Vec<u64>,
+    given: Vec<Option<Timestamp>>,  // both times of the access units with a decoding time, in stream order
+    frame_rate: FrameRate,
+}
+
+impl Timeline {
+    // constant frame rate
+    pub fn constant(frame_rate: FrameRate) -> Timeline {
+        Timeline{times: vec![], given: vec![], frame_rate}
+    }
+
+    // times of the access units in stream order, the presentation times may come in any order. The frame
+    // rate only times the frames the list doesn't cover. A frame with a decoding time keeps its own presentation time.
+    pub fn from_millis(frames: &[FrameTime], frame_rate: FrameRate) -> Timeline {
+        let ticks = |ms: f64| (ms * TIMESTAMPS_TIMESCALE as f64 / 1000.0).round() as u64;
+        let mut times: Vec<u64> = frames.iter().map(|frame| ticks(frame.pts)).collect();
+        times.sort_unstable();
+        let given = frames.iter().map(|frame| frame.dts.map(|dts| Timestamp{dts: ticks(dts), pts: ticks(frame.pts)})).collect();
+        let sample_duration = frame_rate.sample_duration as u64 * TIMESTAMPS_TIMESCALE as u64 / frame_rate.timescale as u64;
+        Timeline{times, given, frame_rate: FrameRate{timescale: TIMESTAMPS_TIMESCALE, sample_duration: sample_duration.max(1) as u32}}
+    }
+
+    pub fn frame_rate(&self) -> FrameRate { self.frame_rate }
+
+    // presentation time of the k-th frame in presentation order
+    pub fn at(&self, k: usize) -> u64 {
+        let duration = self.frame_rate.sample_duration as u64;
+        match self.times.last() {
+            Some(last) if k >= self.times.len() => last + (k + 1 - self.times.len()) as u64 * duration,
+            Some(_) => self.times[k],
+            None => k as u64 * duration,
+        }
+    }
+
+    // decoding time of the index-th access unit of the stream, the index-th presentation time
+    // when it has none of its own
+    pub fn decode_time(&self, index: usize) -> u64 {
+        self.given.get(index).copied().flatten().map_or_else(|| self.at(index), |given| given.dts)
+    }
+
+    // Timestamps of a group of frames in decoding order, given by their position in the stream. Frames
+    // dropped from the group keep their time, the frame before them lasts longer. Without a decoding time
+    // of its own the n-th frame is decoded at the n-th presentation time, so decoding times never go back.
+    // Frames are presented at the time of the frame their composition offset in frames points to, or at
+    // their own presentation time when the list gives their decoding time.
+    pub fn group(&self, indices: &[usize], composition_offsets: &[i32]) -> Vec<Timestamp> {
+        composition_offsets.iter().zip(indices).enumerate().map(|(i, (offset, index))| {
+            if let Some(given) = self.given.get(*index).copied().flatten() { return given; }
+            let display = match indices.get((i as i64 + *offset as i64).max(0) as usize) {
+                Some(display) => *display,
+                None => (*index as i64 + *offset as i64).max(0) as usize,
+            };
+            Timestamp{dts: self.at(*index), pts: self.at(display)}
+        }).collect()
+    }
+}
+
+// mkvmerge timestamp format v2: a "# timestamp format v2" header line (older files say timecode),
+// then the presentation time of every frame in milliseconds, one per line. A second column, if any,
+// is the decoding time of the frame, the lines are then in decoding order.
+pub fn read_v2(path: &str) -> Result<Vec<FrameTime>, ()> {
+    let text = std::fs::read_to_string(path).map_err(|e| eprintln!("Can't read {}: {}", path, e))?;
+    parse_v2(&text, path)
+}
+
+fn parse_v2(text: &str, path: &str) -> Result<Vec<FrameTime>, ()> {
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or("").trim().to_lowercase();
+    if header != "# timestamp format v2" && header != "# timecode format v2" {
+        eprintln!("{} is not a timestamp format v2 file", path);
+        return Err(());
+    }
+
+    let parse = |field: &str| field.parse::<f64>().ok().filter(|ms| ms.is_finite() && *ms >= 0.0);
+    let mut frames = vec![];
+    for (number, line) in lines.enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let mut fields = line.split_whitespace();
+        let pts = fields.next().and_then(parse);
+        let dts = fields.next().map(parse);
+        match (pts, dts, fields.next()) {
+            (Some(pts), None, None) => frames.push(FrameTime{pts, dts: None}),
+            (Some(pts), Some(Some(dts)), None) => frames.push(FrameTime{pts, dts: Some(dts)}),
+            _ => { eprintln!("Can't parse timestamp '{}' at line {}", line, number + 2); return Err(()); }
+        }
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FPS_30: FrameRate = FrameRate{timescale: 90000, sample_duration: 3000};
+
+    fn frames(pts: &[f64], dts: &[Option<f64>]) -> Vec<FrameTime> {
+        pts.iter().zip(dts).map(|(pts, dts)| FrameTime{pts: *pts, dts: *dts}).collect()
+    }
+
+    #[test]
+    fn dropped_frames_keep_their_time() {
+        let timeline = Timeline::from_millis(&frames(&[0.0, 40.0, 80.0, 120.0], &[None; 4]), FPS_30);
+        // the frame at 40 ms was dropped, the frame after the list follows at the frame rate
+        let group = timeline.group(&[0, 2, 3], &[0, 0, 0]);
+        assert_eq!(group.iter().map(|t| t.dts).collect::<Vec<_>>(), [0, 7200, 10800]);
+        assert_eq!(timeline.decode_time(4), 13800);
+    }
+
+    #[test]
+    fn decoding_times_of_the_list() {
+        // I P B in decoding order, the B frame is presented before the P frame
+        let timeline = Timeline::from_millis(&frames(&[40.0, 120.0, 80.0], &[Some(0.0), Some(40.0), Some(80.0)]), FPS_30);
+        let group = timeline.group(&[0, 1, 2], &[0, 1, -1]);
+        assert_eq!(group, [
+            Timestamp{dts: 0, pts: 3600},
+            Timestamp{dts: 3600, pts: 10800},
+            Timestamp{dts: 7200, pts: 7200},
+        ]);
+    }
+
+    #[test]
+    fn presentation_times_of_the_lines() {
+        // the composition offsets of the stream disagree with the list, the list wins
+        let timeline = Timeline::from_millis(&frames(&[40.0, 120.0, 80.0], &[Some(0.0), Some(40.0), Some(80.0)]), FPS_30);
+        let pts: Vec<u64> = timeline.group(&[0, 1, 2], &[0, 0, 0]).iter().map(|t| t.pts).collect();
+        assert_eq!(pts, [3600, 10800, 7200]);
+    }
+
+    #[test]
+    fn v2_headers() {
+        for header in ["# timestamp format v2", "# timecode format v2", "  # Timestamp Format V2 "] {
+            assert_eq!(parse_v2(&format!("{}\n0\n40\n", header), "test"), Ok(frames(&[0.0, 40.0], &[None, None])));
+        }
+        for text in ["", "0\n40\n", "# timestamp format v1\n0\n", "# timestamp format v4\n0\n"] {
+            assert_eq!(parse_v2(text, "test"), Err(()));
+        }
+    }
+
+    #[test]
+    fn v2_lines() {
+        let text = "# timestamp format v2\n\n0 0\n# comment\n  80.5\t40 \n";
+        assert_eq!(parse_v2(text, "test"), Ok(frames(&[0.0, 80.5], &[Some(0.0), Some(40.0)])));
+        for line in ["abc", "-5", "nan", "inf", "40 x", "40 -1", "0 1 2"] {
+            assert_eq!(parse_v2(&format!("# timestamp format v2\n0\n{}\n", line), "test"), Err(()), "{}", line);
+        }
+    }
+}